@@ -0,0 +1,62 @@
+use cgmath::{Point3, point3};
+
+use crate::raytracing::Ray;
+
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Point3<f64>,
+    pub max: Point3<f64>
+}
+
+impl Aabb {
+
+    pub fn new(a: Point3<f64>, b: Point3<f64>) -> Self {
+        Aabb {
+            min: point3(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+            max: point3(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z))
+        }
+    }
+
+    pub fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.dir[axis];
+            let mut near = (self.min[axis] - r.origin[axis]) * inv_d;
+            let mut far = (self.max[axis] - r.origin[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaN (ray parallel to and on a slab) must not shrink the interval
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t1 <= t0 {
+                return None
+            }
+        }
+
+        Some((t0, t1))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: point3(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z)
+            ),
+            max: point3(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z)
+            )
+        }
+    }
+
+}
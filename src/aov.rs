@@ -0,0 +1,206 @@
+use cgmath::{Point3, Vector3, InnerSpace, point3, vec3};
+use prisma::Rgb;
+use std::collections::HashMap;
+
+use crate::camera::Camera;
+use crate::color::ColorSpace;
+use crate::hdr::HdrImage;
+use crate::hittable::HitRecord;
+use crate::material::Material;
+use crate::raytracing::Ray;
+use crate::util::add_colors;
+
+
+// Radiance split by the kind of the first bounce, specular being the delta lobes of
+// mirrors and glass, and by whether it came straight from an emitter after that bounce.
+// Emitters and background seen by camera rays belong to neither.
+#[derive(Clone, Copy)]
+pub struct Lobes {
+    pub direct_diffuse: Rgb<f64>,
+    pub indirect_diffuse: Rgb<f64>,
+    pub direct_specular: Rgb<f64>,
+    pub indirect_specular: Rgb<f64>
+}
+
+impl Lobes {
+
+    pub fn new() -> Self {
+        let black = Rgb::new(0.0, 0.0, 0.0);
+        Lobes {
+            direct_diffuse: black,
+            indirect_diffuse: black,
+            direct_specular: black,
+            indirect_specular: black
+        }
+    }
+
+    pub fn add(&mut self, specular: bool, direct: bool, c: &Rgb<f64>) {
+        let lobe = match (specular, direct) {
+            (false, true) => &mut self.direct_diffuse,
+            (false, false) => &mut self.indirect_diffuse,
+            (true, true) => &mut self.direct_specular,
+            (true, false) => &mut self.indirect_specular
+        };
+        *lobe = add_colors(lobe, c);
+    }
+
+    pub fn map<F: Fn(&Rgb<f64>) -> Rgb<f64>>(&self, f: F) -> Self {
+        Lobes {
+            direct_diffuse: f(&self.direct_diffuse),
+            indirect_diffuse: f(&self.indirect_diffuse),
+            direct_specular: f(&self.direct_specular),
+            indirect_specular: f(&self.indirect_specular)
+        }
+    }
+
+    fn merge(&self, other: &Lobes) -> Self {
+        Lobes {
+            direct_diffuse: add_colors(&self.direct_diffuse, &other.direct_diffuse),
+            indirect_diffuse: add_colors(&self.indirect_diffuse, &other.indirect_diffuse),
+            direct_specular: add_colors(&self.direct_specular, &other.direct_specular),
+            indirect_specular: add_colors(&self.indirect_specular, &other.indirect_specular)
+        }
+    }
+
+}
+
+
+// What an integrator reports about one camera sample besides its radiance
+pub struct SampleAovs<'a> {
+    pub lobes: Lobes,
+    // First hit of the camera ray and the weight its scattering gave the path, which
+    // averages to the albedo over the samples of a pixel
+    pub first_hit: Option<(HitRecord<'a>, Rgb<f64>)>
+}
+
+impl<'a> SampleAovs<'a> {
+
+    pub fn new() -> Self {
+        SampleAovs { lobes: Lobes::new(), first_hit: None }
+    }
+
+    // Converts the colors, for instance from the wavelengths of a spectral path to RGB
+    pub fn map<F: Fn(&Rgb<f64>) -> Rgb<f64>>(&self, f: F) -> Self {
+        SampleAovs {
+            lobes: self.lobes.map(&f),
+            first_hit: self.first_hit.map(|(rec, albedo)| (rec, f(&albedo)))
+        }
+    }
+
+}
+
+
+// Auxiliary values of one pixel. Albedo, normal and lobes are summed over its samples,
+// the rest is taken from the nearest first hit among them.
+#[derive(Clone, Copy)]
+pub struct PixelAovs {
+    albedo: Rgb<f64>,
+    normal: Vector3<f64>,
+    lobes: Lobes,
+    depth: f64,
+    position: Point3<f64>,
+    object_id: Option<usize>,
+    // Address of the material, made into an index once the whole image is known
+    material: Option<usize>
+}
+
+impl PixelAovs {
+
+    pub fn new() -> Self {
+        PixelAovs {
+            albedo: Rgb::new(0.0, 0.0, 0.0),
+            normal: vec3(0.0, 0.0, 0.0),
+            lobes: Lobes::new(),
+            depth: std::f64::INFINITY,
+            position: point3(0.0, 0.0, 0.0),
+            object_id: None,
+            material: None
+        }
+    }
+
+    pub fn add_sample(&mut self, r: &Ray, camera: &Camera, sample: &SampleAovs) {
+        self.lobes = self.lobes.merge(&sample.lobes);
+
+        let (rec, albedo) = match &sample.first_hit {
+            Some(first_hit) => first_hit,
+            None => return
+        };
+        self.albedo = add_colors(&self.albedo, albedo);
+        self.normal += rec.normal;
+
+        let depth = (rec.p - r.origin).dot(camera.forward());
+        if depth < self.depth {
+            self.depth = depth;
+            self.position = rec.p;
+            self.object_id = Some(rec.object_id);
+            self.material = rec.material.map(|m| m as *const dyn Material as *const () as usize);
+        }
+    }
+
+    // Lobes of light tracing contributions landing on this pixel
+    pub fn add_lobes(&mut self, lobes: &Lobes) {
+        self.lobes = self.lobes.merge(lobes);
+    }
+
+    pub fn merge(&self, other: &PixelAovs) -> Self {
+        let nearest = if other.depth < self.depth { other } else { self };
+        PixelAovs {
+            albedo: add_colors(&self.albedo, &other.albedo),
+            normal: self.normal + other.normal,
+            lobes: self.lobes.merge(&other.lobes),
+            ..*nearest
+        }
+    }
+
+}
+
+
+// A named image along with the names of its channels
+pub struct Aov {
+    pub name: &'static str,
+    pub channels: &'static [&'static str],
+    pub image: HdrImage,
+    // IDs, depths and positions, which must not be stored at reduced precision
+    pub exact: bool
+}
+
+
+// Turns the pixels accumulated over `samples` samples each into AOV images, colors
+// being in `working_space`. Object and material indices start at one, zero is left
+// for the background. Materials are numbered in order of appearance in the image.
+pub fn collect(pixels: &[PixelAovs], width: usize, height: usize, samples: usize,
+               working_space: ColorSpace) -> Vec<Aov> {
+    let k = 1.0 / samples as f64;
+    let rgb = |c: &Rgb<f64>| vec![c.red() * k, c.green() * k, c.blue() * k];
+
+    let mut material_ids = HashMap::new();
+    for material in pixels.iter().filter_map(|p| p.material) {
+        let next = material_ids.len() + 1;
+        material_ids.entry(material).or_insert(next);
+    }
+
+    let layer = |name, channels: &'static [&'static str], space, exact, f: &dyn Fn(&PixelAovs) -> Vec<f64>| {
+        Aov {
+            name,
+            channels,
+            image: HdrImage::from_fn(width, height, channels.len(), space, |i| f(&pixels[i])),
+            exact
+        }
+    };
+
+    vec![
+        layer("albedo", &["R", "G", "B"], working_space, false, &|p| rgb(&p.albedo)),
+        layer("normal", &["X", "Y", "Z"], ColorSpace::Raw, false, &|p| {
+            let n = if p.normal.magnitude2() > 0.0 { p.normal.normalize() } else { p.normal };
+            vec![n.x, n.y, n.z]
+        }),
+        layer("depth", &["Z"], ColorSpace::Raw, true, &|p| vec![p.depth]),
+        layer("position", &["X", "Y", "Z"], ColorSpace::Raw, true, &|p| vec![p.position.x, p.position.y, p.position.z]),
+        layer("object_id", &["id"], ColorSpace::Raw, true, &|p| vec![p.object_id.map_or(0.0, |id| (id + 1) as f64)]),
+        layer("material_id", &["id"], ColorSpace::Raw, true, &|p| vec![p.material.map_or(0.0, |m| material_ids[&m] as f64)]),
+        layer("direct_diffuse", &["R", "G", "B"], working_space, false, &|p| rgb(&p.lobes.direct_diffuse)),
+        layer("indirect_diffuse", &["R", "G", "B"], working_space, false, &|p| rgb(&p.lobes.indirect_diffuse)),
+        layer("direct_specular", &["R", "G", "B"], working_space, false, &|p| rgb(&p.lobes.direct_specular)),
+        layer("indirect_specular", &["R", "G", "B"], working_space, false, &|p| rgb(&p.lobes.indirect_specular))
+    ]
+}
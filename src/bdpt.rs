@@ -0,0 +1,499 @@
+use cgmath::{Point3, InnerSpace};
+use prisma::Rgb;
+use rand::Rng;
+use std::f64::consts::PI;
+
+use crate::aov::SampleAovs;
+use crate::camera::Camera;
+use crate::hittable::{Hittable, HitRecord};
+use crate::material::ScatterRecord;
+use crate::raytracing::{Ray, background};
+use crate::scene::World;
+use crate::spectrum::Wavelengths;
+use crate::util::{Onb, add_colors, mul_colors, random_cosine_direction, scale_color};
+
+
+fn is_black(c: &Rgb<f64>) -> bool {
+    c.red() <= 0.0 && c.green() <= 0.0 && c.blue() <= 0.0
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface
+}
+
+
+#[derive(Clone, Copy)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    rec: HitRecord<'a>,
+    // Ray the vertex was reached by along its own subpath
+    ray_in: Ray,
+    beta: Rgb<f64>,
+    // Whether the subpath left through a delta lobe
+    delta: bool,
+    // Whether there are lobes other than delta ones to connect through, whichever lobe
+    // the subpath left through
+    connectible: bool,
+    // Area densities of sampling this vertex from its predecessor along the subpath
+    // and, in reverse, from its successor
+    pdf_fwd: f64,
+    pdf_rev: f64
+}
+
+impl<'a> Vertex<'a> {
+
+    fn p(&self) -> Point3<f64> {
+        self.rec.p
+    }
+
+    fn on_surface(&self) -> bool {
+        match self.kind {
+            VertexKind::Camera => false,
+            VertexKind::Light => true,
+            VertexKind::Surface => !self.rec.material.map_or(false, |m| m.is_volumetric())
+        }
+    }
+
+    // The record as seen by a ray arriving from `from`
+    fn facing(&self, from: Point3<f64>) -> (Ray, HitRecord<'a>) {
+        let incoming = Ray { origin: from, dir: self.p() - from, wavelengths: self.ray_in.wavelengths, channel: self.ray_in.channel };
+        let outward = if self.rec.front_face { self.rec.normal } else { -self.rec.normal };
+        let mut rec = self.rec;
+        rec.set_face_normal(&incoming, &outward);
+        (incoming, rec)
+    }
+
+    // BSDF times cosine for light leaving along the subpath's incoming ray towards `to`
+    fn eval(&self, to: Point3<f64>) -> Rgb<f64> {
+        let scattered = Ray { origin: self.p(), dir: to - self.p(), wavelengths: self.ray_in.wavelengths, channel: self.ray_in.channel };
+        self.rec.material.unwrap().eval(&self.ray_in, &self.rec, &scattered)
+    }
+
+    fn emitted(&self, to: Point3<f64>) -> Rgb<f64> {
+        let (incoming, rec) = self.facing(to);
+        self.rec.material.unwrap().emitted(&incoming, &rec)
+    }
+
+    // Solid angle density of scattering from `from` towards `to`
+    fn scatter_pdf(&self, from: Point3<f64>, to: Point3<f64>) -> f64 {
+        let (incoming, rec) = self.facing(from);
+        let mut srec = ScatterRecord::new();
+        if !self.rec.material.unwrap().scatter(&incoming, &rec, &mut srec) {
+            return 0.0
+        }
+        srec.pdf.map_or(0.0, |pdf| pdf.value(&(to - self.p())))
+    }
+
+    // Solid angle density of cosine distributed emission towards `to`
+    fn emission_pdf(&self, to: Point3<f64>) -> f64 {
+        let outward = if self.rec.front_face { self.rec.normal } else { -self.rec.normal };
+        let cos_theta = outward.dot((to - self.p()).normalize());
+        if cos_theta > 0.0 { cos_theta / PI } else { 0.0 }
+    }
+
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p() - self.p();
+        let dist2 = w.magnitude2();
+        if dist2 == 0.0 {
+            return 0.0
+        }
+        let mut pdf = pdf / dist2;
+        if next.on_surface() {
+            pdf *= next.rec.normal.dot(w).abs() / dist2.sqrt();
+        }
+        pdf
+    }
+
+}
+
+
+pub struct Bidirectional<'a> {
+    pub camera: &'a Camera,
+    pub max_depth: usize,
+    pub rr_min_depth: usize,
+    // Ratio of the sampled film area to the [0, 1] square, see `Camera::importance`
+    pub film_scale: f64
+}
+
+impl<'a> Bidirectional<'a> {
+
+    // Radiance carried by the camera ray `r`, split into lobes in `aovs`. Light tracing
+    // contributions landing on other pixels are pushed to `splats` with their film
+    // coordinates and the number of bounces of their paths.
+    pub fn sample<'w>(&self, r: &Ray, world: &'w World, splats: &mut Vec<(f64, f64, Rgb<f64>, usize)>,
+                      aovs: &mut SampleAovs<'w>) -> Rgb<f64> {
+        let mut camera_path = Vec::with_capacity(self.max_depth + 2);
+        let mut light_path = Vec::with_capacity(self.max_depth + 2);
+
+        let mut radiance = self.camera_subpath(r, world, &mut camera_path, aovs);
+        self.light_subpath(r.wavelengths, world, &mut light_path);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if s + t < 2 || s + t - 2 > self.max_depth {
+                    continue
+                }
+
+                if t == 1 {
+                    if let Some((u, v, l)) = self.connect_camera(&light_path, s, world) {
+                        splats.push((u, v, l, s - 1));
+                    }
+                } else {
+                    let l = self.connect(&light_path, &camera_path, s, t, world);
+                    if s + t > 2 {
+                        aovs.lobes.add(t > 2 && camera_path[1].delta, s + t == 3, &l);
+                    }
+                    radiance = add_colors(&radiance, &l);
+                }
+            }
+        }
+
+        radiance
+    }
+
+    // Returns radiance from the background and from emitters that are not lights, which
+    // only camera paths can find and which are therefore not weighted
+    fn camera_subpath<'w>(&self, r: &Ray, world: &'w World, path: &mut Vec<Vertex<'w>>,
+                          aovs: &mut SampleAovs<'w>) -> Rgb<f64> {
+        let mut rec = HitRecord::new();
+        rec.p = r.origin;
+        rec.normal = self.camera.forward();
+        path.push(Vertex {
+            kind: VertexKind::Camera,
+            rec,
+            ray_in: *r,
+            beta: Rgb::new(1.0, 1.0, 1.0),
+            delta: false,
+            connectible: true,
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0
+        });
+
+        let pdf_dir = self.camera.pdf_dir(&r.dir, self.film_scale);
+        self.random_walk(*r, Rgb::new(1.0, 1.0, 1.0), pdf_dir, world, path, Some(aovs))
+    }
+
+    fn light_subpath<'w>(&self, wavelengths: Option<Wavelengths>, world: &'w World,
+                        path: &mut Vec<Vertex<'w>>) {
+        if world.lights.is_empty() {
+            return
+        }
+
+        let mut rng = rand::thread_rng();
+        let light_id = world.lights[rng.gen_range(0..world.lights.len())];
+        let light = &world.objects[light_id];
+        let area = light.area();
+        let mut rec = HitRecord::new();
+        if area <= 0.0 || !light.sample_surface(&mut rec) {
+            return
+        }
+        rec.object_id = light_id;
+
+        let pdf_pos = 1.0 / (world.lights.len() as f64 * area);
+        let d = random_cosine_direction();
+        let dir = Onb::from_w(rec.normal).local(d.x, d.y, d.z);
+        let pdf_dir = d.z / PI;
+        if pdf_dir <= 0.0 {
+            return
+        }
+
+        let ray = Ray { origin: rec.p, dir, wavelengths, channel: None };
+        let vertex = Vertex {
+            kind: VertexKind::Light,
+            rec,
+            ray_in: ray,
+            beta: scale_color(&Rgb::new(1.0, 1.0, 1.0), 1.0 / pdf_pos),
+            delta: false,
+            connectible: true,
+            pdf_fwd: pdf_pos,
+            pdf_rev: 0.0
+        };
+        path.push(vertex);
+
+        // Cosine over the direction density leaves a factor of pi
+        let beta = scale_color(&vertex.emitted(rec.p + dir), PI / pdf_pos);
+        self.random_walk(ray, beta, pdf_dir, world, path, None);
+    }
+
+    // Camera subpaths come with `aovs`, to record their first hit and the lobes of the
+    // radiance they find by themselves
+    fn random_walk<'w>(&self, r: Ray, beta: Rgb<f64>, pdf_dir: f64, world: &'w World,
+                       path: &mut Vec<Vertex<'w>>, mut aovs: Option<&mut SampleAovs<'w>>) -> Rgb<f64> {
+        let mut radiance = Rgb::new(0.0, 0.0, 0.0);
+        let mut beta = beta;
+        let mut pdf_dir = pdf_dir;
+        let mut ray = r;
+        let mut throughput = Rgb::new(1.0, 1.0, 1.0);
+        let mut srec = ScatterRecord::new();
+
+        for depth in 0..self.max_depth {
+            let mut rec = HitRecord::new();
+            if !world.hit(&ray, 0.001, std::f64::INFINITY, &mut rec) {
+                if let Some(aovs) = aovs.as_mut() {
+                    let escaped = mul_colors(&beta, &background(&ray, world));
+                    if depth > 0 {
+                        aovs.lobes.add(path[1].delta, depth == 1, &escaped);
+                    }
+                    radiance = add_colors(&radiance, &escaped);
+                }
+                break
+            }
+
+            let prev = path.len() - 1;
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface,
+                rec,
+                ray_in: ray,
+                beta,
+                delta: false,
+                connectible: true,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0
+            };
+            vertex.pdf_fwd = path[prev].convert_density(pdf_dir, &vertex);
+
+            let material = rec.material.unwrap();
+            if let Some(aovs) = aovs.as_mut() {
+                if depth == 0 {
+                    aovs.first_hit = Some((rec, Rgb::new(0.0, 0.0, 0.0)));
+                }
+                if !world.is_light(rec.object_id) {
+                    let emitted = mul_colors(&beta, &material.emitted(&ray, &rec));
+                    if depth > 0 {
+                        aovs.lobes.add(path[1].delta, depth == 1, &emitted);
+                    }
+                    radiance = add_colors(&radiance, &emitted);
+                }
+            }
+
+            if !material.scatter(&ray, &rec, &mut srec) {
+                path.push(vertex);
+                break
+            }
+
+            let scattered;
+            let pdf_rev;
+            let pdf = srec.pdf.take();
+            vertex.connectible = pdf.is_some();
+            if srec.is_specular {
+                vertex.delta = true;
+                scattered = srec.specular_ray;
+                beta = mul_colors(&beta, &srec.attenuation);
+                throughput = mul_colors(&throughput, &srec.attenuation);
+                pdf_dir = 0.0;
+                pdf_rev = 0.0;
+            } else {
+                let pdf = pdf.unwrap();
+                scattered = Ray { origin: rec.p, dir: pdf.generate(), wavelengths: ray.wavelengths, channel: ray.channel };
+                pdf_dir = pdf.value(&scattered.dir);
+                if pdf_dir <= 0.0 {
+                    path.push(vertex);
+                    break
+                }
+
+                let f = scale_color(&material.eval(&ray, &rec, &scattered), 1.0 / pdf_dir);
+                beta = mul_colors(&beta, &f);
+                throughput = mul_colors(&throughput, &f);
+                pdf_rev = vertex.scatter_pdf(scattered.at(1.0), path[prev].p());
+            }
+
+            if let (0, Some(Some(first_hit))) = (depth, aovs.as_mut().map(|a| a.first_hit.as_mut())) {
+                first_hit.1 = throughput;
+            }
+            path[prev].pdf_rev = vertex.convert_density(pdf_rev, &path[prev]);
+            path.push(vertex);
+            ray = scattered;
+
+            if depth + 1 >= self.rr_min_depth {
+                let survival = throughput.red().max(throughput.green()).max(throughput.blue()).min(0.95);
+                if survival <= 0.0 || rand::thread_rng().gen::<f64>() >= survival {
+                    break
+                }
+                beta = scale_color(&beta, 1.0 / survival);
+                throughput = scale_color(&throughput, 1.0 / survival);
+            }
+        }
+
+        radiance
+    }
+
+    // Media in the way are sampled at the wavelengths and channel of `along`
+    fn visible(&self, a: Point3<f64>, b: Point3<f64>, along: &Ray, world: &World) -> bool {
+        let shadow_ray = Ray { origin: a, dir: b - a, wavelengths: along.wavelengths, channel: along.channel };
+        let mut rec = HitRecord::new();
+        !world.hit(&shadow_ray, 0.001, 1.0 - 0.001, &mut rec)
+    }
+
+    // Contribution of the light subpath vertex `qs` towards `to`, cosine included
+    fn light_end(&self, qs: &Vertex, to: Point3<f64>) -> Rgb<f64> {
+        if qs.kind == VertexKind::Light {
+            let w = (to - qs.p()).normalize();
+            scale_color(&mul_colors(&qs.beta, &qs.emitted(to)), qs.rec.normal.dot(w).abs())
+        } else {
+            mul_colors(&qs.beta, &qs.eval(to))
+        }
+    }
+
+    fn connect(&self, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize,
+               world: &World) -> Rgb<f64> {
+        let black = Rgb::new(0.0, 0.0, 0.0);
+        let pt = &camera_path[t - 1];
+
+        let l = if s == 0 {
+            // The camera subpath ran into a light by itself
+            if pt.kind != VertexKind::Surface || !world.is_light(pt.rec.object_id) {
+                return black
+            }
+            mul_colors(&pt.beta, &pt.rec.material.unwrap().emitted(&pt.ray_in, &pt.rec))
+        } else {
+            let qs = &light_path[s - 1];
+            if !pt.connectible || !qs.connectible {
+                return black
+            }
+            // Each subpath makes up for the wavelengths it dropped, which only needs
+            // doing once when both did
+            let hero_only = |v: &Vertex| v.ray_in.wavelengths.map_or(false, |w| w.is_hero_only());
+            let dropped = if hero_only(pt) && hero_only(qs) { 3.0 } else { 1.0 };
+            let dist2 = (qs.p() - pt.p()).magnitude2();
+            let l = scale_color(&mul_colors(&mul_colors(&pt.beta, &pt.eval(qs.p())), &self.light_end(qs, pt.p())),
+                          1.0 / (dist2 * dropped));
+            // Along the channel either subpath was narrowed down to, if any
+            let along = if qs.ray_in.channel.is_some() || hero_only(qs) { &qs.ray_in } else { &pt.ray_in };
+            if is_black(&l) || !self.visible(pt.p(), qs.p(), along, world) {
+                return black
+            }
+            l
+        };
+
+        if is_black(&l) {
+            return black
+        }
+        scale_color(&l, self.mis_weight(light_path, camera_path, None, s, t, world))
+    }
+
+    // Light tracing strategies, connecting a light subpath vertex to a new lens sample
+    fn connect_camera(&self, light_path: &[Vertex], s: usize, world: &World)
+        -> Option<(f64, f64, Rgb<f64>)> {
+        let qs = &light_path[s - 1];
+        if !qs.connectible {
+            return None
+        }
+
+        let lens_point = self.camera.sample_lens();
+        let (u, v) = self.camera.raster(lens_point, qs.p())?;
+        let dir = qs.p() - lens_point;
+        let dist2 = dir.magnitude2();
+        let cos_theta = dir.normalize().dot(self.camera.forward());
+        let importance = self.camera.importance(&dir, self.film_scale) * self.camera.lens_area();
+
+        let l = scale_color(&self.light_end(qs, lens_point), importance * cos_theta / dist2);
+        if is_black(&l) || !self.visible(lens_point, qs.p(), &qs.ray_in, world) {
+            return None
+        }
+
+        let mut rec = HitRecord::new();
+        rec.p = lens_point;
+        rec.normal = self.camera.forward();
+        let sampled = Vertex {
+            kind: VertexKind::Camera,
+            rec,
+            ray_in: Ray { origin: lens_point, dir, wavelengths: qs.ray_in.wavelengths, channel: qs.ray_in.channel },
+            beta: Rgb::new(importance, importance, importance),
+            delta: false,
+            connectible: true,
+            pdf_fwd: 1.0 / self.camera.lens_area(),
+            pdf_rev: 0.0
+        };
+
+        let weight = self.mis_weight(light_path, &[], Some(sampled), s, 1, world);
+        Some((u, v, scale_color(&l, weight)))
+    }
+
+    // Area density of `v` sampling `next`, having been reached from `prev`
+    fn pdf(&self, v: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let pdf_dir = match v.kind {
+            VertexKind::Camera => self.camera.pdf_dir(&(next.p() - v.p()), self.film_scale),
+            VertexKind::Light => v.emission_pdf(next.p()),
+            VertexKind::Surface => v.scatter_pdf(prev.unwrap().p(), next.p())
+        };
+        v.convert_density(pdf_dir, next)
+    }
+
+    // Area density of light sampling picking the point of `v` on its light
+    fn pdf_light_origin(&self, v: &Vertex, world: &World) -> f64 {
+        if !world.is_light(v.rec.object_id) {
+            return 0.0
+        }
+        let area = world.objects[v.rec.object_id].area();
+        if area > 0.0 { 1.0 / (world.lights.len() as f64 * area) } else { 0.0 }
+    }
+
+    // Power heuristic over all strategies that could have produced the same path
+    fn mis_weight(&self, light_path: &[Vertex], camera_path: &[Vertex], sampled: Option<Vertex>,
+                  s: usize, t: usize, world: &World) -> f64 {
+        if s + t == 2 {
+            return 1.0
+        }
+
+        let pt = sampled.unwrap_or_else(|| camera_path[t - 1]);
+        let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+        let qs = if s > 0 { Some(&light_path[s - 1]) } else { None };
+        let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+
+        // (pdf_fwd, pdf_rev, delta) of both subpaths, with the reverse densities at and
+        // next to the connection replaced by those of the connected path
+        let mut light: Vec<(f64, f64, bool)> = light_path[..s].iter()
+            .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+            .collect();
+        let mut camera: Vec<(f64, f64, bool)> = match sampled {
+            Some(v) => vec![(v.pdf_fwd, v.pdf_rev, v.delta)],
+            None => camera_path[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect()
+        };
+
+        camera[t - 1].2 = false;
+        camera[t - 1].1 = match qs {
+            Some(qs) => self.pdf(qs, qs_minus, &pt),
+            None => self.pdf_light_origin(&pt, world)
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera[t - 2].1 = match qs {
+                Some(qs) => self.pdf(&pt, Some(qs), pt_minus),
+                None => pt.convert_density(pt.emission_pdf(pt_minus.p()), pt_minus)
+            };
+        }
+        if let Some(qs) = qs {
+            light[s - 1].2 = false;
+            light[s - 1].1 = self.pdf(&pt, pt_minus, qs);
+        }
+        if let (Some(qs), Some(qs_minus)) = (qs, qs_minus) {
+            light[s - 2].1 = self.pdf(qs, Some(&pt), qs_minus);
+        }
+
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(camera[i].1) / remap(camera[i].0);
+            if !camera[i].2 && !camera[i - 1].2 {
+                sum += ri * ri;
+            }
+        }
+
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(light[i].1) / remap(light[i].0);
+            let delta_light = if i > 0 { light[i - 1].2 } else { false };
+            if !light[i].2 && !delta_light {
+                sum += ri * ri;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+}
@@ -1,4 +1,5 @@
 use cgmath::{Point3, Vector3, InnerSpace};
+use std::f64::consts::PI;
 
 use crate::raytracing::Ray;
 use crate::util::random_vec_in_unit_disk;
@@ -60,8 +61,73 @@ impl Camera {
 
         Ray {
             origin: self.origin + offset,
-            dir: self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset
+            dir: self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset,
+            wavelengths: None,
+            channel: None
         }
     }
 
+    pub fn forward(&self) -> Vector3<f64> {
+        -self.w
+    }
+
+    // Lens area, taken as one for a pinhole so it cancels out of importance and pdfs
+    pub fn lens_area(&self) -> f64 {
+        if self.lens_radius > 0.0 { PI * self.lens_radius * self.lens_radius } else { 1.0 }
+    }
+
+    pub fn sample_lens(&self) -> Point3<f64> {
+        let rd = random_vec_in_unit_disk() * self.lens_radius;
+        self.origin + self.u * rd.x + self.v * rd.y
+    }
+
+    // Film coordinates (s, t), as taken by `get_ray`, of the ray leaving the lens at
+    // `lens_point` towards `p`. They may fall outside of [0, 1] as the film is
+    // sampled slightly beyond it.
+    pub fn raster(&self, lens_point: Point3<f64>, p: Point3<f64>) -> Option<(f64, f64)> {
+        let dir = p - lens_point;
+        let cos_theta = dir.dot(self.forward());
+        if cos_theta <= 0.0 {
+            return None
+        }
+
+        // Point on the plane of focus the ray passes through
+        let focus_dist = (self.origin - self.lower_left_corner).dot(self.w);
+        let depth = focus_dist - (lens_point - self.origin).dot(self.forward());
+        let focus_point = lens_point + dir * (depth / cos_theta);
+        let offset = focus_point - self.lower_left_corner;
+        Some((
+            offset.dot(self.horizontal) / self.horizontal.magnitude2(),
+            offset.dot(self.vertical) / self.vertical.magnitude2()
+        ))
+    }
+
+    // Area at unit distance covered by the film for (s, t) in [0, 1]
+    fn film_area(&self) -> f64 {
+        let focus_dist = (self.origin - self.lower_left_corner).dot(self.w);
+        self.horizontal.magnitude() * self.vertical.magnitude() / (focus_dist * focus_dist)
+    }
+
+    // Solid angle density of camera rays in direction `dir`, when the sampled part of
+    // the film is `film_scale` times the [0, 1] square
+    pub fn pdf_dir(&self, dir: &Vector3<f64>, film_scale: f64) -> f64 {
+        let cos_theta = dir.normalize().dot(self.forward());
+        if cos_theta <= 0.0 {
+            return 0.0
+        }
+        1.0 / (self.film_area() * film_scale * cos_theta * cos_theta * cos_theta)
+    }
+
+    // Importance of a ray leaving the lens in direction `dir`, normalized over the
+    // whole sampled film so that light tracing contributions summed into a pixel and
+    // divided by its sample count estimate the pixel's radiance
+    pub fn importance(&self, dir: &Vector3<f64>, film_scale: f64) -> f64 {
+        let cos_theta = dir.normalize().dot(self.forward());
+        if cos_theta <= 0.0 {
+            return 0.0
+        }
+        let cos2 = cos_theta * cos_theta;
+        1.0 / (self.film_area() * film_scale * self.lens_area() * cos2 * cos2)
+    }
+
 }
@@ -0,0 +1,230 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use prisma::Rgb;
+use rand::Rng;
+use std::sync::Arc;
+
+use crate::color::{Color, ColorSpace};
+use crate::hittable::HitRecord;
+use crate::material::{Material, ScatterRecord};
+use crate::microfacet::{MicrofacetPdf, TrowbridgeReitz, fresnel_dielectric, reflect, shading_frame, to_local};
+use crate::pdf::{MixturePdf, Pdf};
+use crate::raytracing::Ray;
+use crate::spectrum::sample_rgb;
+use crate::util::{mul_colors, scale_color};
+
+
+// A thin dielectric layer over another material, such as varnish over wood or the
+// clearcoat of car paint. The base is lit through the coat, losing what the top
+// interface reflects on the way in and out and what the coat absorbs along the
+// refracted path. Interreflections inside the coat are ignored.
+pub struct Coated {
+    base: Arc<dyn Material>,
+    ior: f64,
+    distribution: TrowbridgeReitz,
+    // Transmittance for light crossing the coat once at normal incidence
+    tint: Rgb<f64>
+}
+
+impl Coated {
+
+    pub fn new(base: Arc<dyn Material>, ior: f64, roughness: f64) -> Self {
+        Coated {
+            base,
+            ior,
+            distribution: TrowbridgeReitz::new(roughness, roughness),
+            tint: Rgb::new(1.0, 1.0, 1.0)
+        }
+    }
+
+    pub fn with_tint<C: Into<Color>>(mut self, tint: C, space: ColorSpace) -> Self {
+        self.tint = tint.into().to(space);
+        self
+    }
+
+    // Fresnel transmittance of the top interface and absorption in the coat for light
+    // crossing it along `w`, a local direction
+    fn transmittance(&self, w: &Vector3<f64>, r: &Ray) -> Rgb<f64> {
+        let cos = w.z.abs();
+        let sin2_t = (1.0 - cos * cos) / (self.ior * self.ior);
+        let cos_t = (1.0 - sin2_t).max(1e-4).sqrt();
+        let tint = sample_rgb(&self.tint, &r.wavelengths);
+        let t = 1.0 - fresnel_dielectric(cos, self.ior);
+        let absorbed = |v: f64| t * v.max(1e-6).powf(1.0 / cos_t);
+        Rgb::new(absorbed(tint.red()), absorbed(tint.green()), absorbed(tint.blue()))
+    }
+
+    // Attenuation of base scattering from `wo` to `wi`. Legs leaving through the bottom
+    // of a transmissive base do not cross the coat.
+    fn through_coat(&self, wo: &Vector3<f64>, wi: &Vector3<f64>, r: &Ray) -> Rgb<f64> {
+        let to = self.transmittance(wo, r);
+        if wi.z <= 0.0 {
+            return to
+        }
+        mul_colors(&to, &self.transmittance(wi, r))
+    }
+
+    // Probability of sampling the coat rather than the base
+    fn coat_probability(&self, wo: &Vector3<f64>) -> f64 {
+        fresnel_dielectric(wo.z, self.ior).max(0.1).min(0.9)
+    }
+
+}
+
+impl Material for Coated {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        if !rec.front_face {
+            return self.base.scatter(r, rec, srec)
+        }
+
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        if wo.z <= 0.0 {
+            return false
+        }
+
+        // Absorbing bases still show the coat
+        let base_scatters = self.base.scatter(r, rec, srec);
+        let base_specular = base_scatters && srec.is_specular;
+        let base_pdf = if base_scatters { srec.pdf.take() } else { None };
+        let p = if base_scatters { self.coat_probability(&wo) } else { 1.0 };
+        let smooth = self.distribution.is_smooth();
+
+        // Densities of the lobes that are not delta ones, weighted by the probability of
+        // picking them. Next-event estimation relies on them whichever lobe is picked.
+        let mut pdfs: Vec<(f64, Box<dyn Pdf>)> = Vec::new();
+        if !smooth {
+            pdfs.push((p, Box::new(MicrofacetPdf::new(frame, wo, self.distribution, None))));
+        }
+        if let (Some(base_pdf), false) = (base_pdf, base_specular) {
+            pdfs.push((1.0 - p, base_pdf));
+        }
+        srec.pdf = if pdfs.is_empty() { None } else { Some(Box::new(MixturePdf::new(pdfs))) };
+
+        let coat = rand::thread_rng().gen::<f64>() < p;
+        if coat && smooth {
+            let f = fresnel_dielectric(wo.z, self.ior) / p;
+            srec.attenuation = Rgb::new(f, f, f);
+            srec.is_specular = true;
+            let wi = reflect(&wo, &vec3(0.0, 0.0, 1.0));
+            srec.specular_ray = Ray {
+                origin: rec.p,
+                dir: frame.local(wi.x, wi.y, wi.z),
+                wavelengths: r.wavelengths,
+                channel: r.channel
+            };
+            return true
+        }
+
+        if !coat && base_specular {
+            let wi = to_local(&frame, &srec.specular_ray.dir.normalize());
+            srec.attenuation = scale_color(&mul_colors(&srec.attenuation, &self.through_coat(&wo, &wi, r)), 1.0 / (1.0 - p));
+            return true
+        }
+
+        // The picked lobe is among those of the mixture, which samples it by itself
+        srec.is_specular = false;
+        srec.pdf.is_some()
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        if !rec.front_face {
+            return self.base.eval(r, rec, scattered)
+        }
+
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        let wi = to_local(&frame, &scattered.dir.normalize());
+        if wo.z <= 0.0 {
+            return Rgb::new(0.0, 0.0, 0.0)
+        }
+
+        let base = mul_colors(&self.base.eval(r, rec, scattered), &self.through_coat(&wo, &wi, r));
+        if self.distribution.is_smooth() || wi.z <= 0.0 {
+            return base
+        }
+
+        let wm = (wo + wi).normalize();
+        let coat = self.distribution.d(&wm) * self.distribution.g(&wo, &wi)
+            * fresnel_dielectric(wo.dot(wm), self.ior) / (4.0 * wo.z);
+        Rgb::new(base.red() + coat, base.green() + coat, base.blue() + coat)
+    }
+
+    fn emitted(&self, r: &Ray, rec: &HitRecord) -> Rgb<f64> {
+        self.base.emitted(r, rec)
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{point3, vec3};
+
+    use crate::aov::SampleAovs;
+    use crate::geometry::{Quad, Sphere};
+    use crate::material::{DiffuseLight, Lambertian, Metal};
+    use crate::raytracing::{PathTracer, SamplingStrategy};
+    use crate::scene::World;
+    use crate::util::random_vec_in_unit_disk;
+
+    // White furnace: a coated sphere inside a closed box whose walls are all white lights
+    fn furnace(material: Arc<dyn Material>) -> World {
+        let space = ColorSpace::LinearSrgb;
+        let mut world = World::new(space);
+        world.background = Some(Rgb::new(0.0, 0.0, 0.0));
+        world.objects.push(Box::new(Sphere { center: point3(0.0, 0.0, 0.0), radius: 1.0, mat: material }));
+
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(Rgb::new(1.0, 1.0, 1.0), space));
+        let (x, y, z) = (vec3(6.0, 0.0, 0.0), vec3(0.0, 6.0, 0.0), vec3(0.0, 0.0, 6.0));
+        // Corners and edges ordered so that the normals face inwards
+        let walls = [
+            (point3(-3.0, -3.0, -3.0), z, x),
+            (point3(-3.0, 3.0, -3.0), x, z),
+            (point3(-3.0, -3.0, -3.0), y, z),
+            (point3(3.0, -3.0, -3.0), z, y),
+            (point3(-3.0, -3.0, -3.0), x, y),
+            (point3(-3.0, -3.0, 3.0), y, x)
+        ];
+        for (q, u, v) in walls.iter() {
+            world.add_light(Box::new(Quad::new(*q, *u, *v, light.clone())));
+        }
+        world
+    }
+
+    // Mean radiance of rays from in front of the sphere aimed at it
+    fn render(world: &World, sampling: SamplingStrategy, samples: usize) -> f64 {
+        let integrator = PathTracer { max_depth: 6, rr_min_depth: 6, sampling };
+        let origin = point3(0.0, 0.0, 2.5);
+        let sum: f64 = (0..samples).map(|_| {
+            let target = random_vec_in_unit_disk() * 0.9;
+            let r = Ray { origin, dir: point3(target.x, target.y, 0.0) - origin, wavelengths: None, channel: None };
+            let c = integrator.ray_color(&r, world, &mut SampleAovs::new());
+            (c.red() + c.green() + c.blue()) / 3.0
+        }).sum();
+        sum / samples as f64
+    }
+
+    #[test]
+    fn sampling_strategies_agree_in_a_furnace() {
+        let space = ColorSpace::LinearSrgb;
+        let white = Rgb::new(1.0, 1.0, 1.0);
+        let materials: Vec<(&str, Arc<dyn Material>)> = vec![
+            ("smooth coat", Arc::new(Coated::new(Arc::new(Lambertian::new(white, space)), 1.5, 0.0))),
+            ("rough coat", Arc::new(Coated::new(Arc::new(Lambertian::new(white, space)), 1.5, 0.3))),
+            ("rough coat over a mirror", Arc::new(Coated::new(Arc::new(Metal::new(white, 0.0, space)), 1.5, 0.3)))
+        ];
+
+        for (name, material) in materials {
+            let world = furnace(material);
+            let bsdf = render(&world, SamplingStrategy::Bsdf, 200_000);
+            let light = render(&world, SamplingStrategy::Light, 200_000);
+            let mis = render(&world, SamplingStrategy::Mis, 200_000);
+            for v in [bsdf, light].iter() {
+                assert!((v - mis).abs() < 0.015 * mis, "{}: bsdf {} light {} mis {}", name, bsdf, light, mis);
+            }
+        }
+    }
+
+}
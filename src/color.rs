@@ -0,0 +1,270 @@
+use prisma::Rgb;
+
+
+type Matrix = [[f64; 3]; 3];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0]
+];
+
+// Bradford adapted between the D65 white of Rec. 709 and the D60 one of ACES
+const REC709_TO_ACESCG: Matrix = [
+    [0.613_097_402_4, 0.339_523_146_2, 0.047_379_451_4],
+    [0.070_193_722_5, 0.916_353_879_1, 0.013_452_398_5],
+    [0.020_615_592_9, 0.109_569_772_9, 0.869_814_634_2]
+];
+
+const ACESCG_TO_REC709: Matrix = [
+    [1.705_050_992_7, -0.621_792_120_7, -0.083_258_872_0],
+    [-0.130_256_417_5, 1.140_804_736_6, -0.010_548_319_1],
+    [-0.024_003_356_8, -0.128_968_976_1, 1.152_972_332_9]
+];
+
+const REC709_TO_REC2020: Matrix = [
+    [0.627_403_9, 0.329_283_0, 0.043_313_1],
+    [0.069_097_3, 0.919_540_4, 0.011_362_3],
+    [0.016_391_4, 0.088_013_3, 0.895_595_3]
+];
+
+const REC2020_TO_REC709: Matrix = [
+    [1.660_491_0, -0.587_641_1, -0.072_849_9],
+    [-0.124_550_5, 1.132_899_9, -0.008_349_4],
+    [-0.018_150_8, -0.100_578_9, 1.118_729_7]
+];
+
+
+fn apply(m: &Matrix, c: &Rgb<f64>) -> Rgb<f64> {
+    let (r, g, b) = (c.red(), c.green(), c.blue());
+    Rgb::new(
+        m[0][0] * r + m[0][1] * g + m[0][2] * b,
+        m[1][0] * r + m[1][1] * g + m[1][2] * b,
+        m[2][0] * r + m[2][1] * g + m[2][2] * b
+    )
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorSpace {
+    // Data that is not a color, such as normals or depth, left untouched
+    Raw,
+    // Rec. 709 primaries, linear
+    LinearSrgb,
+    // Rec. 709 primaries with the sRGB transfer function, as in 8-bit images
+    Srgb,
+    // ACES AP1 primaries, linear
+    AcesCg,
+    // Rec. 2020 primaries, linear
+    Rec2020
+}
+
+impl ColorSpace {
+
+    fn to_rec709(&self) -> &Matrix {
+        match self {
+            ColorSpace::AcesCg => &ACESCG_TO_REC709,
+            ColorSpace::Rec2020 => &REC2020_TO_REC709,
+            _ => &IDENTITY
+        }
+    }
+
+    fn from_rec709(&self) -> &Matrix {
+        match self {
+            ColorSpace::AcesCg => &REC709_TO_ACESCG,
+            ColorSpace::Rec2020 => &REC709_TO_REC2020,
+            _ => &IDENTITY
+        }
+    }
+
+    pub fn convert(&self, c: &Rgb<f64>, to: ColorSpace) -> Rgb<f64> {
+        if *self == to || *self == ColorSpace::Raw || to == ColorSpace::Raw {
+            return *c
+        }
+
+        let linear = match self {
+            ColorSpace::Srgb => Rgb::new(srgb_eotf(c.red()), srgb_eotf(c.green()), srgb_eotf(c.blue())),
+            _ => *c
+        };
+        let converted = apply(to.from_rec709(), &apply(self.to_rec709(), &linear));
+        match to {
+            ColorSpace::Srgb => Rgb::new(
+                srgb_oetf(converted.red()),
+                srgb_oetf(converted.green()),
+                srgb_oetf(converted.blue())
+            ),
+            _ => converted
+        }
+    }
+
+    // CIE xy of the red, green and blue primaries and of the white point
+    pub fn chromaticities(&self) -> Option<[f64; 8]> {
+        match self {
+            ColorSpace::Raw => None,
+            ColorSpace::LinearSrgb | ColorSpace::Srgb =>
+                Some([0.64, 0.33, 0.30, 0.60, 0.15, 0.06, 0.3127, 0.3290]),
+            ColorSpace::AcesCg =>
+                Some([0.713, 0.293, 0.165, 0.830, 0.128, 0.044, 0.32168, 0.33767]),
+            ColorSpace::Rec2020 =>
+                Some([0.708, 0.292, 0.170, 0.797, 0.131, 0.046, 0.3127, 0.3290])
+        }
+    }
+
+}
+
+
+// A color tagged with the space it is given in. Untagged colors are linear sRGB.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Color {
+    pub rgb: Rgb<f64>,
+    pub space: ColorSpace
+}
+
+impl Color {
+
+    pub fn new(rgb: Rgb<f64>, space: ColorSpace) -> Self {
+        Color { rgb, space }
+    }
+
+    pub fn to(&self, space: ColorSpace) -> Rgb<f64> {
+        self.space.convert(&self.rgb, space)
+    }
+
+}
+
+impl From<Rgb<f64>> for Color {
+
+    fn from(rgb: Rgb<f64>) -> Self {
+        Color::new(rgb, ColorSpace::LinearSrgb)
+    }
+
+}
+
+
+pub fn srgb_eotf(v: f64) -> f64 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+
+// sRGB encoding of a linear value in [0, 1]
+pub fn srgb_oetf(v: f64) -> f64 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPACES: [ColorSpace; 3] = [ColorSpace::LinearSrgb, ColorSpace::AcesCg, ColorSpace::Rec2020];
+
+    fn assert_close(a: &Rgb<f64>, b: &Rgb<f64>, tolerance: f64) {
+        let d = (a.red() - b.red()).abs().max((a.green() - b.green()).abs()).max((a.blue() - b.blue()).abs());
+        assert!(d < tolerance, "{:?} != {:?}", a, b);
+    }
+
+    fn inverse(m: &Matrix) -> Matrix {
+        let cofactor = |r: usize, c: usize| {
+            let (r0, r1, c0, c1) = ((r + 1) % 3, (r + 2) % 3, (c + 1) % 3, (c + 2) % 3);
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+        let det = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum::<f64>();
+        let mut inv = [[0.0; 3]; 3];
+        for (r, row) in inv.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = cofactor(c, r) / det;
+            }
+        }
+        inv
+    }
+
+    // RGB to XYZ matrix derived from the chromaticities of a space
+    fn to_xyz(space: ColorSpace) -> Matrix {
+        let xy = space.chromaticities().unwrap();
+        let column = |x: f64, y: f64| [x / y, 1.0, (1.0 - x - y) / y];
+        let p = [column(xy[0], xy[1]), column(xy[2], xy[3]), column(xy[4], xy[5])];
+        let primaries = [
+            [p[0][0], p[1][0], p[2][0]],
+            [p[0][1], p[1][1], p[2][1]],
+            [p[0][2], p[1][2], p[2][2]]
+        ];
+        let w = column(xy[6], xy[7]);
+        let s = apply(&inverse(&primaries), &Rgb::new(w[0], w[1], w[2]));
+        let s = [s.red(), s.green(), s.blue()];
+        let mut m = primaries;
+        for row in m.iter_mut() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v *= s[c];
+            }
+        }
+        m
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        let colors = [Rgb::new(1.0, 0.0, 0.0), Rgb::new(0.0, 1.0, 0.0), Rgb::new(0.0, 0.0, 1.0), Rgb::new(0.3, 0.7, 0.2)];
+        for &from in SPACES.iter() {
+            for &to in SPACES.iter() {
+                for c in colors.iter() {
+                    assert_close(&to.convert(&from.convert(c, to), from), c, 1e-6);
+                }
+            }
+        }
+        for c in colors.iter() {
+            let encoded = ColorSpace::LinearSrgb.convert(c, ColorSpace::Srgb);
+            assert_close(&ColorSpace::Srgb.convert(&encoded, ColorSpace::LinearSrgb), c, 1e-9);
+        }
+    }
+
+    #[test]
+    fn white_stays_white() {
+        let white = Rgb::new(1.0, 1.0, 1.0);
+        for &from in SPACES.iter() {
+            for &to in SPACES.iter() {
+                assert_close(&from.convert(&white, to), &white, 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn primaries_keep_their_xyz() {
+        // Rec. 709 and Rec. 2020 share the D65 white, so converting between them must
+        // not move a color in XYZ
+        let (rec709, rec2020) = (to_xyz(ColorSpace::LinearSrgb), to_xyz(ColorSpace::Rec2020));
+        for c in [Rgb::new(1.0, 0.0, 0.0), Rgb::new(0.0, 1.0, 0.0), Rgb::new(0.0, 0.0, 1.0)].iter() {
+            let converted = ColorSpace::LinearSrgb.convert(c, ColorSpace::Rec2020);
+            assert_close(&apply(&rec2020, &converted), &apply(&rec709, c), 1e-5);
+        }
+
+        // ACEScg has a D60 white, which the conversion reaches through Bradford adaptation
+        let bradford = [
+            [0.8951, 0.2664, -0.1614],
+            [-0.7502, 1.7135, 0.0367],
+            [0.0389, -0.0685, 1.0296]
+        ];
+        let acescg = to_xyz(ColorSpace::AcesCg);
+        let white = |m: &Matrix| apply(&bradford, &apply(m, &Rgb::new(1.0, 1.0, 1.0)));
+        let (src, dst) = (white(&rec709), white(&acescg));
+        let adapt = |xyz: &Rgb<f64>| {
+            let lms = apply(&bradford, xyz);
+            apply(&inverse(&bradford), &Rgb::new(
+                lms.red() * dst.red() / src.red(),
+                lms.green() * dst.green() / src.green(),
+                lms.blue() * dst.blue() / src.blue()
+            ))
+        };
+        for c in [Rgb::new(1.0, 0.0, 0.0), Rgb::new(0.0, 1.0, 0.0), Rgb::new(0.0, 0.0, 1.0)].iter() {
+            let converted = ColorSpace::LinearSrgb.convert(c, ColorSpace::AcesCg);
+            assert_close(&apply(&acescg, &converted), &adapt(&apply(&rec709, c)), 1e-4);
+        }
+    }
+
+}
@@ -0,0 +1,363 @@
+use cgmath::{Point3, Vector3, InnerSpace, EuclideanSpace, vec3};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::animation::Animated;
+use crate::raytracing::Ray;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::util::Onb;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CurveType {
+    // Flat strip always facing the incoming ray
+    Ribbon,
+    // Round tube, approximated by offsetting the ribbon hit along the curve's radius
+    Tube
+}
+
+
+fn bezier(cp: &[Vector3<f64>; 4], u: f64) -> (Vector3<f64>, Vector3<f64>) {
+    let s = 1.0 - u;
+    let point = cp[0] * (s * s * s) + cp[1] * (3.0 * s * s * u) + cp[2] * (3.0 * s * u * u)
+        + cp[3] * (u * u * u);
+    let derivative = (cp[1] - cp[0]) * (3.0 * s * s) + (cp[2] - cp[1]) * (6.0 * s * u)
+        + (cp[3] - cp[2]) * (3.0 * u * u);
+    (point, derivative)
+}
+
+
+fn subdivide(cp: &[Vector3<f64>; 4]) -> ([Vector3<f64>; 4], [Vector3<f64>; 4]) {
+    let a = (cp[0] + cp[1]) * 0.5;
+    let b = (cp[1] + cp[2]) * 0.5;
+    let c = (cp[2] + cp[3]) * 0.5;
+    let ab = (a + b) * 0.5;
+    let bc = (b + c) * 0.5;
+    let mid = (ab + bc) * 0.5;
+    ([cp[0], a, ab, mid], [mid, bc, c, cp[3]])
+}
+
+
+struct CurveHit {
+    z: f64,
+    u: f64,
+    v: f64
+}
+
+
+// Cubic Bezier segment with a width varying linearly along it
+pub struct Curve {
+    cp: [Vector3<f64>; 4],
+    width0: f64,
+    width1: f64,
+    kind: CurveType,
+    bounds: Aabb,
+    mat: Arc<dyn Material>
+}
+
+impl Curve {
+
+    pub fn new(cp: [Point3<f64>; 4], width0: f64, width1: f64, kind: CurveType,
+               mat: Arc<dyn Material>) -> Self {
+        let half_width = 0.5 * width0.max(width1);
+        let pad = vec3(half_width, half_width, half_width);
+        let bounds = cp.iter()
+            .map(|&p| Aabb::new(p - pad, p + pad))
+            .fold(Aabb::new(cp[0], cp[0]), |acc, b| acc.union(&b));
+
+        Curve {
+            cp: [cp[0].to_vec(), cp[1].to_vec(), cp[2].to_vec(), cp[3].to_vec()],
+            width0,
+            width1,
+            kind,
+            bounds,
+            mat
+        }
+    }
+
+    fn width(&self, u: f64) -> f64 {
+        self.width0 + (self.width1 - self.width0) * u
+    }
+
+    // Subdivision depth at which the segments are flat enough to be treated as lines
+    fn max_depth(&self, cp: &[Vector3<f64>; 4]) -> usize {
+        let mut l0: f64 = 0.0;
+        for i in 0..2 {
+            let d = cp[i] - cp[i + 1] * 2.0 + cp[i + 2];
+            l0 = l0.max(d.x.abs()).max(d.y.abs()).max(d.z.abs());
+        }
+        let eps = 0.05 * self.width0.max(self.width1);
+        if l0 <= 0.0 || eps <= 0.0 {
+            return 0
+        }
+        let depth = 0.5 * (std::f64::consts::SQRT_2 * 6.0 * l0 / (8.0 * eps)).log2();
+        depth.round().max(0.0).min(10.0) as usize
+    }
+
+    fn intersect(&self, cp: &[Vector3<f64>; 4], u0: f64, u1: f64, depth: usize, z_min: f64,
+                 hit: &mut Option<CurveHit>) {
+
+        let z_max = hit.as_ref().map_or(std::f64::INFINITY, |h| h.z);
+
+        // Cull against the control points' bounds in ray space, the ray running along +z
+        let half_width = 0.5 * self.width(u0).max(self.width(u1));
+        let (mut lo, mut hi) = (cp[0], cp[0]);
+        for p in cp.iter() {
+            lo = vec3(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z));
+            hi = vec3(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z));
+        }
+        if lo.x - half_width > 0.0 || hi.x + half_width < 0.0 ||
+            lo.y - half_width > 0.0 || hi.y + half_width < 0.0 ||
+            hi.z + half_width < z_min || lo.z - half_width > z_max {
+            return
+        }
+
+        if depth > 0 {
+            let (near, far) = subdivide(cp);
+            let u_mid = 0.5 * (u0 + u1);
+            self.intersect(&near, u0, u_mid, depth - 1, z_min, hit);
+            self.intersect(&far, u_mid, u1, depth - 1, z_min, hit);
+            return
+        }
+
+        // The ray must pass between the planes through the segment's end points
+        let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+        if edge < 0.0 {
+            return
+        }
+        let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+        if edge < 0.0 {
+            return
+        }
+
+        let seg = vec3(cp[3].x - cp[0].x, cp[3].y - cp[0].y, 0.0);
+        let denom = seg.magnitude2();
+        if denom == 0.0 {
+            return
+        }
+        let w = ((-cp[0].x * seg.x - cp[0].y * seg.y) / denom).max(0.0).min(1.0);
+
+        let u = u0 + (u1 - u0) * w;
+        let hit_width = self.width(u);
+        let (pc, dpcdw) = bezier(cp, w);
+        let dist2 = pc.x * pc.x + pc.y * pc.y;
+        let radius2 = 0.25 * hit_width * hit_width;
+        if dist2 > radius2 {
+            return
+        }
+
+        let z = match self.kind {
+            CurveType::Ribbon => pc.z,
+            CurveType::Tube => pc.z - (radius2 - dist2).sqrt()
+        };
+        if z < z_min || z > z_max {
+            return
+        }
+
+        let dist = dist2.sqrt();
+        let side = dpcdw.x * -pc.y + pc.x * dpcdw.y;
+        let v = if side > 0.0 { 0.5 + dist / hit_width } else { 0.5 - dist / hit_width };
+        *hit = Some(CurveHit { z, u, v });
+    }
+
+}
+
+impl Hittable for Curve {
+
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        if self.bounds.intersect(r, t_min, t_max).is_none() {
+            return false
+        }
+
+        let ray_length = r.dir.magnitude();
+        let frame = Onb::from_w(r.dir);
+        let origin = r.origin.to_vec();
+        let to_ray_space = |p: Vector3<f64>| {
+            let q = p - origin;
+            vec3(q.dot(frame.u), q.dot(frame.v), q.dot(frame.w))
+        };
+        let cp = [to_ray_space(self.cp[0]), to_ray_space(self.cp[1]),
+                  to_ray_space(self.cp[2]), to_ray_space(self.cp[3])];
+
+        let mut hit = None;
+        self.intersect(&cp, 0.0, 1.0, self.max_depth(&cp), t_min * ray_length, &mut hit);
+        let hit = match hit {
+            Some(hit) if hit.z <= t_max * ray_length => hit,
+            _ => return false
+        };
+
+        rec.t = hit.z / ray_length;
+        rec.p = r.at(rec.t);
+        rec.u = hit.u;
+        rec.v = hit.v;
+        rec.material = Some(self.mat.as_ref());
+
+        let (axis_point, derivative) = bezier(&self.cp, hit.u);
+        let tangent = derivative.normalize();
+        let facing = -frame.w - tangent * (-frame.w).dot(tangent);
+        let outward_normal = match self.kind {
+            CurveType::Ribbon => facing,
+            CurveType::Tube => {
+                let offset = rec.p.to_vec() - axis_point;
+                let radial = offset - tangent * offset.dot(tangent);
+                if radial.magnitude2() > 0.0 { radial } else { facing }
+            }
+        }.normalize();
+
+        rec.tangent = tangent;
+        rec.set_face_normal(r, &outward_normal);
+        true
+    }
+
+}
+
+
+// Node of the bounding volume hierarchy over a `CurveSet`, children and leaf curves
+// being stored as index ranges
+struct BvhNode {
+    bounds: Aabb,
+    // First curve of a leaf, or the second child of an interior node, the first child
+    // always directly following its parent
+    offset: usize,
+    // Number of curves in a leaf, zero for interior nodes
+    count: usize
+}
+
+const BVH_LEAF_SIZE: usize = 4;
+
+
+pub struct CurveSet {
+    curves: Vec<Curve>,
+    nodes: Vec<BvhNode>
+}
+
+impl CurveSet {
+
+    pub fn new(mut curves: Vec<Curve>) -> Self {
+        let mut nodes = Vec::new();
+        if !curves.is_empty() {
+            build_bvh(&mut curves, 0, &mut nodes);
+        }
+        CurveSet { curves, nodes }
+    }
+
+    // Text strand file: one strand per line as whitespace separated "x y z width"
+    // control points, 3n + 1 of them forming n cubic segments sharing end points.
+    // Empty lines and lines starting with '#' are ignored.
+    pub fn load<P: AsRef<Path>>(path: P, kind: CurveType, mat: Arc<dyn Material>)
+        -> Result<Self, Error> {
+
+        let reader = BufReader::new(File::open(path)?);
+        let mut curves = Vec::new();
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            let invalid = |msg: &str| {
+                Error::new(ErrorKind::InvalidData, format!("line {}: {}", line_number + 1, msg))
+            };
+            let values = line.split_whitespace()
+                .map(|v| v.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|_| invalid("expected numbers"))?;
+            if values.len() % 4 != 0 || values.len() < 16 || (values.len() / 4 - 1) % 3 != 0 {
+                return Err(invalid("a strand needs 3n + 1 control points of x y z width"))
+            }
+
+            let points: Vec<(Point3<f64>, f64)> = values.chunks_exact(4)
+                .map(|c| (Point3::new(c[0], c[1], c[2]), c[3]))
+                .collect();
+            for seg in points.windows(4).step_by(3) {
+                curves.push(Curve::new(
+                    [seg[0].0, seg[1].0, seg[2].0, seg[3].0],
+                    seg[0].1,
+                    seg[3].1,
+                    kind,
+                    mat.clone()
+                ));
+            }
+        }
+
+        Ok(CurveSet::new(curves))
+    }
+
+}
+
+impl Hittable for CurveSet {
+
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        if self.nodes.is_empty() {
+            return false
+        }
+
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.bounds.intersect(r, t_min, closest_so_far).is_none() {
+                continue
+            }
+            if node.count == 0 {
+                stack.push(node.offset);
+                stack.push(index + 1);
+                continue
+            }
+            for curve in &self.curves[node.offset..node.offset + node.count] {
+                if curve.hit(r, t_min, closest_so_far, rec) {
+                    hit_anything = true;
+                    closest_so_far = rec.t;
+                }
+            }
+        }
+        hit_anything
+    }
+
+}
+
+// Splits `curves` at the median of their centers along the longest axis of those
+// centers, appending the nodes depth first. `first` is the index of `curves[0]` in
+// the whole set.
+fn build_bvh(curves: &mut [Curve], first: usize, nodes: &mut Vec<BvhNode>) {
+    let bounds = curves[1..].iter().fold(curves[0].bounds, |acc, c| acc.union(&c.bounds));
+    let index = nodes.len();
+    if curves.len() <= BVH_LEAF_SIZE {
+        nodes.push(BvhNode { bounds, offset: first, count: curves.len() });
+        return
+    }
+
+    let center = |c: &Curve| (c.bounds.min.to_vec() + c.bounds.max.to_vec()) * 0.5;
+    let (lo, hi) = curves.iter().fold(
+        (vec3(f64::MAX, f64::MAX, f64::MAX), vec3(f64::MIN, f64::MIN, f64::MIN)),
+        |(lo, hi), c| {
+            let p = center(c);
+            (vec3(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z)), vec3(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z)))
+        }
+    );
+    let extent = hi - lo;
+    let axis = if extent.x > extent.y && extent.x > extent.z { 0 } else if extent.y > extent.z { 1 } else { 2 };
+
+    let mid = curves.len() / 2;
+    curves.select_nth_unstable_by(mid, |a, b| center(a)[axis].partial_cmp(&center(b)[axis]).unwrap());
+
+    nodes.push(BvhNode { bounds, offset: 0, count: 0 });
+    let (left, right) = curves.split_at_mut(mid);
+    build_bvh(left, first, nodes);
+    nodes[index].offset = nodes.len();
+    build_bvh(right, first + mid, nodes);
+}
+
+
+impl Animated for CurveSet {
+
+    fn update(&mut self, _time: f64) {}
+
+}
@@ -0,0 +1,564 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+
+use crate::hdr::HdrImage;
+use crate::rendering::Framebuffer;
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PixelType {
+    Half,
+    Float
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    None,
+    Rle,
+    // Zlib over single scanlines
+    Zips,
+    // Zlib over blocks of sixteen scanlines
+    Zip
+}
+
+impl Compression {
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Rle => 1,
+            Compression::Zips => 2,
+            Compression::Zip => 3
+        }
+    }
+
+    fn lines_per_chunk(&self) -> usize {
+        match self {
+            Compression::Zip => 16,
+            _ => 1
+        }
+    }
+
+}
+
+
+// Channels of `image` written under `prefix`, e.g. "albedo." for "albedo.R". Channels
+// are taken from the image in the order of `channels`.
+pub struct Layer<'a> {
+    pub prefix: String,
+    pub channels: &'a [&'a str],
+    pub image: &'a HdrImage,
+    pub pixel_type: PixelType
+}
+
+
+// Beauty as plain R, G and B, and every AOV as a layer named after it. `pixel_type`
+// applies to the colors, AOVs that need their precision are always stored as floats.
+pub fn write_framebuffer(path: &str, framebuffer: &Framebuffer, pixel_type: PixelType,
+                         compression: Compression) -> Result<(), Error> {
+    let mut layers = vec![Layer {
+        prefix: String::new(),
+        channels: &["R", "G", "B"],
+        image: &framebuffer.beauty,
+        pixel_type
+    }];
+    for aov in framebuffer.aovs.iter() {
+        layers.push(Layer {
+            prefix: format!("{}.", aov.name),
+            channels: aov.channels,
+            image: &aov.image,
+            pixel_type: if aov.exact { PixelType::Float } else { pixel_type }
+        });
+    }
+
+    write(path, &layers, compression)
+}
+
+
+// Single part scanline OpenEXR file. All layers must have the same size, the color
+// space of the first one is recorded as the file's chromaticities.
+pub fn write(path: &str, layers: &[Layer], compression: Compression) -> Result<(), Error> {
+    let width = layers[0].image.width;
+    let height = layers[0].image.height;
+
+    // Channels are stored in alphabetical order, each one as (name, image, index, type)
+    let mut channels: Vec<(String, &HdrImage, usize, PixelType)> = layers.iter()
+        .flat_map(|layer| layer.channels.iter().enumerate()
+            .map(move |(i, name)| (format!("{}{}", layer.prefix, name), layer.image, i, layer.pixel_type)))
+        .collect();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+    let long_names = channels.iter().any(|c| c.0.len() > 31);
+    header.extend_from_slice(&(2u32 | if long_names { 0x400 } else { 0 }).to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for (name, _, _, pixel_type) in channels.iter() {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&(match pixel_type { PixelType::Half => 1i32, PixelType::Float => 2 }).to_le_bytes());
+        // pLinear and reserved bytes, then x and y sampling
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let mut window = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    attribute(&mut header, "channels", "chlist", &chlist);
+    if let Some(xy) = layers[0].image.space.chromaticities() {
+        let values: Vec<u8> = xy.iter().flat_map(|&v| (v as f32).to_le_bytes().to_vec()).collect();
+        attribute(&mut header, "chromaticities", "chromaticities", &values);
+    }
+    attribute(&mut header, "compression", "compression", &[compression.id()]);
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let lines = compression.lines_per_chunk();
+    let chunks: Vec<Vec<u8>> = (0..height).step_by(lines)
+        .map(|y0| {
+            let mut raw = Vec::new();
+            for y in y0..(y0 + lines).min(height) {
+                for (_, image, c, pixel_type) in channels.iter() {
+                    for x in 0..width {
+                        let v = image.data[(y * width + x) * image.channels + c];
+                        match pixel_type {
+                            PixelType::Half => raw.extend_from_slice(&f32_to_f16(v).to_le_bytes()),
+                            PixelType::Float => raw.extend_from_slice(&v.to_le_bytes())
+                        }
+                    }
+                }
+            }
+
+            let data = compress(&raw, compression);
+            let mut chunk = Vec::with_capacity(data.len() + 8);
+            chunk.extend_from_slice(&(y0 as i32).to_le_bytes());
+            chunk.extend_from_slice(&(data.len() as i32).to_le_bytes());
+            chunk.extend_from_slice(&data);
+            chunk
+        })
+        .collect();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&header)?;
+
+    let mut offset = (header.len() + chunks.len() * 8) as u64;
+    for chunk in chunks.iter() {
+        file.write_all(&offset.to_le_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    for chunk in chunks.iter() {
+        file.write_all(chunk)?;
+    }
+
+    file.flush()
+}
+
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+
+// Compressed chunks that end up no smaller than the raw data are stored raw, which
+// readers tell apart by their size
+fn compress(raw: &[u8], compression: Compression) -> Vec<u8> {
+    let data = match compression {
+        Compression::None => return raw.to_vec(),
+        Compression::Rle => rle(&predict(raw)),
+        Compression::Zips | Compression::Zip => deflate::deflate_bytes_zlib(&predict(raw))
+    };
+
+    if data.len() < raw.len() { data } else { raw.to_vec() }
+}
+
+
+// Splits the bytes of the values into two halves, then replaces every byte by its
+// difference to the previous one, which makes smooth images compress better
+fn predict(raw: &[u8]) -> Vec<u8> {
+    let half = (raw.len() + 1) / 2;
+    let mut t = vec![0u8; raw.len()];
+    for (i, &b) in raw.iter().enumerate() {
+        t[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = b;
+    }
+
+    let mut prev = t.first().copied().unwrap_or(0);
+    for b in t.iter_mut().skip(1) {
+        let d = (*b as i32 - prev as i32 + (128 + 256)) as u8;
+        prev = *b;
+        *b = d;
+    }
+    t
+}
+
+
+// Runs of three or more equal bytes as a count minus one and the byte, anything else
+// as a negated count followed by the bytes
+fn rle(data: &[u8]) -> Vec<u8> {
+    const MAX_RUN: usize = 127;
+    let mut out = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        let mut end = start + 1;
+        while end < data.len() && data[start] == data[end] && end - start - 1 < MAX_RUN {
+            end += 1;
+        }
+
+        if end - start >= 3 {
+            out.push((end - start - 1) as u8);
+            out.push(data[start]);
+        } else {
+            while end < data.len() &&
+                (end + 1 >= data.len() || data[end] != data[end + 1] ||
+                 end + 2 >= data.len() || data[end + 1] != data[end + 2]) &&
+                end - start < MAX_RUN {
+                end += 1;
+            }
+            out.push((-((end - start) as i32)) as u8);
+            out.extend_from_slice(&data[start..end]);
+        }
+        start = end;
+    }
+
+    out
+}
+
+
+// Rounds to the nearest half, ties to even, overflowing to infinity
+fn f32_to_f16(v: f32) -> u16 {
+    let bits = v.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 }
+    }
+
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00
+    }
+
+    if e <= 0 {
+        // Subnormal half, or zero when even rounding cannot reach the smallest one
+        if e < -10 {
+            return sign
+        }
+        let m = mantissa | 0x80_0000;
+        let shift = (14 - e) as u32;
+        let half_m = m >> shift;
+        let rest = m & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let rounded = if rest > halfway || (rest == halfway && half_m & 1 == 1) { half_m + 1 } else { half_m };
+        return sign | rounded as u16
+    }
+
+    let mut h = ((e as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    // A carry out of the mantissa correctly bumps the exponent, up to infinity
+    if rest > 0x1000 || (rest == 0x1000 && h & 1 == 1) {
+        h += 1;
+    }
+    sign | h as u16
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::aov::Aov;
+    use crate::color::ColorSpace;
+
+    fn f16_to_f32(h: u16) -> f32 {
+        let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exp = ((h >> 10) & 0x1f) as i32;
+        let mantissa = (h & 0x3ff) as f32;
+        match exp {
+            0 => sign * mantissa * 2f32.powi(-24),
+            0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+            0x1f => f32::NAN,
+            _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exp - 15)
+        }
+    }
+
+    fn unrle(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let count = data[i] as i8;
+            if count < 0 {
+                let n = -(count as i32) as usize;
+                out.extend_from_slice(&data[i + 1..i + 1 + n]);
+                i += 1 + n;
+            } else {
+                out.resize(out.len() + count as usize + 1, data[i + 1]);
+                i += 2;
+            }
+        }
+        out
+    }
+
+    fn unpredict(data: &[u8]) -> Vec<u8> {
+        let mut t = data.to_vec();
+        for i in 1..t.len() {
+            t[i] = (t[i - 1] as i32 + t[i] as i32 - 128) as u8;
+        }
+        let half = t.len().div_ceil(2);
+        (0..t.len()).map(|i| t[if i % 2 == 0 { i / 2 } else { half + i / 2 }]).collect()
+    }
+
+    struct Decoded {
+        width: usize,
+        height: usize,
+        // Name and pixel type id of every channel, in file order
+        channels: Vec<(String, i32)>,
+        values: HashMap<String, Vec<f32>>
+    }
+
+    fn read_str(bytes: &[u8], pos: &mut usize) -> String {
+        let end = *pos + bytes[*pos..].iter().position(|&b| b == 0).unwrap();
+        let s = String::from_utf8(bytes[*pos..end].to_vec()).unwrap();
+        *pos = end + 1;
+        s
+    }
+
+    fn read_i32(bytes: &[u8], pos: usize) -> i32 {
+        i32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    }
+
+    // Minimal reader for the files `write` produces
+    fn decode(bytes: &[u8]) -> Decoded {
+        assert_eq!(&bytes[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        assert_eq!(bytes[4], 2);
+
+        let mut pos = 8;
+        let mut attributes = HashMap::new();
+        loop {
+            let name = read_str(bytes, &mut pos);
+            if name.is_empty() {
+                break
+            }
+            let _kind = read_str(bytes, &mut pos);
+            let size = read_i32(bytes, pos) as usize;
+            attributes.insert(name, bytes[pos + 4..pos + 4 + size].to_vec());
+            pos += 4 + size;
+        }
+
+        let chlist = &attributes["channels"];
+        let mut channels = Vec::new();
+        let mut p = 0;
+        loop {
+            let name = read_str(chlist, &mut p);
+            if name.is_empty() {
+                break
+            }
+            channels.push((name, read_i32(chlist, p)));
+            p += 16;
+        }
+
+        let window = &attributes["dataWindow"];
+        let width = (read_i32(window, 8) + 1) as usize;
+        let height = (read_i32(window, 12) + 1) as usize;
+        let compression = attributes["compression"][0];
+        let lines = if compression == 3 { 16 } else { 1 };
+
+        let mut values: HashMap<String, Vec<f32>> = channels.iter()
+            .map(|(name, _)| (name.clone(), vec![0.0; width * height]))
+            .collect();
+        let chunks = height.div_ceil(lines);
+        for chunk in 0..chunks {
+            let offset_pos = pos + chunk * 8;
+            let mut offset = [0u8; 8];
+            offset.copy_from_slice(&bytes[offset_pos..offset_pos + 8]);
+            let offset = u64::from_le_bytes(offset) as usize;
+
+            let y0 = read_i32(bytes, offset) as usize;
+            let size = read_i32(bytes, offset + 4) as usize;
+            let data = &bytes[offset + 8..offset + 8 + size];
+            let rows = lines.min(height - y0);
+            let raw_size: usize = channels.iter()
+                .map(|(_, t)| if *t == 1 { 2 } else { 4 })
+                .sum::<usize>() * width * rows;
+            let raw = if size == raw_size {
+                data.to_vec()
+            } else if compression == 1 {
+                unpredict(&unrle(data))
+            } else {
+                unpredict(&miniz_oxide::inflate::decompress_to_vec_zlib(data).unwrap())
+            };
+            assert_eq!(raw.len(), raw_size);
+
+            let mut p = 0;
+            for y in y0..y0 + rows {
+                for (name, t) in channels.iter() {
+                    let row = values.get_mut(name).unwrap();
+                    for x in 0..width {
+                        row[y * width + x] = if *t == 1 {
+                            p += 2;
+                            f16_to_f32(u16::from_le_bytes([raw[p - 2], raw[p - 1]]))
+                        } else {
+                            p += 4;
+                            f32::from_le_bytes([raw[p - 4], raw[p - 3], raw[p - 2], raw[p - 1]])
+                        };
+                    }
+                }
+            }
+        }
+
+        Decoded { width, height, channels, values }
+    }
+
+    fn test_image(width: usize, height: usize) -> HdrImage {
+        HdrImage::from_fn(width, height, 3, ColorSpace::LinearSrgb, |i| {
+            let (x, y) = ((i % width) as f64, (i / width) as f64);
+            // A smooth gradient, a flat band and some noise-like values
+            let noise = ((i * 7919) % 101) as f64 / 10.0;
+            vec![x / width as f64, if y < 8.0 { 0.5 } else { y * 3.0 }, noise]
+        })
+    }
+
+    fn round_trip(image: &HdrImage, pixel_type: PixelType, compression: Compression) -> Decoded {
+        let path = std::env::temp_dir().join(format!(
+            "exr-test-{}-{:?}-{:?}.exr", std::process::id(), pixel_type, compression));
+        let path = path.to_str().unwrap();
+        let layer = Layer { prefix: String::new(), channels: &["R", "G", "B"], image, pixel_type };
+        write(path, &[layer], compression).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        decode(&bytes)
+    }
+
+    #[test]
+    fn f16_known_bit_patterns() {
+        let cases: &[(f32, u16)] = &[
+            (0.0, 0x0000),
+            (-0.0, 0x8000),
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (0.1, 0x2e66),
+            (65504.0, 0x7bff),
+            // Halfway to 65536, rounding to even overflows
+            (65520.0, 0x7c00),
+            (1e10, 0x7c00),
+            (f32::INFINITY, 0x7c00),
+            (f32::NEG_INFINITY, 0xfc00),
+            // Smallest normal, and the subnormals below it
+            (2f32.powi(-14), 0x0400),
+            (1023.0 * 2f32.powi(-24), 0x03ff),
+            (2f32.powi(-24), 0x0001),
+            (1.5 * 2f32.powi(-24), 0x0002),
+            (2f32.powi(-25), 0x0000),
+            (1.5 * 2f32.powi(-25), 0x0001),
+            (2f32.powi(-30), 0x0000),
+            // Ties to even on either side
+            (1.0 + 2f32.powi(-11), 0x3c00),
+            (1.0 + 3.0 * 2f32.powi(-11), 0x3c02),
+            // Carry out of the mantissa
+            (2.0 - 2f32.powi(-12), 0x4000)
+        ];
+        for &(v, bits) in cases {
+            assert_eq!(f32_to_f16(v), bits, "{:e}", v);
+        }
+
+        let nan = f32_to_f16(f32::NAN);
+        assert_eq!(nan & 0x7c00, 0x7c00);
+        assert_ne!(nan & 0x3ff, 0);
+    }
+
+    #[test]
+    fn f16_round_trips_every_half() {
+        for bits in 0..=0xffffu16 {
+            let v = f16_to_f32(bits);
+            if v.is_nan() {
+                continue
+            }
+            assert_eq!(f32_to_f16(v), bits);
+        }
+    }
+
+    #[test]
+    fn rle_known_runs() {
+        assert_eq!(rle(&[5, 5, 5, 5]), vec![3, 5]);
+        assert_eq!(rle(&[1, 2, 3]), vec![-3i8 as u8, 1, 2, 3]);
+        assert_eq!(rle(&[1, 2, 2, 2, 2]), vec![-1i8 as u8, 1, 3, 2]);
+        assert_eq!(rle(&[7; 200]), vec![127, 7, 71, 7]);
+        assert!(rle(&[]).is_empty());
+    }
+
+    #[test]
+    fn rle_and_predictor_round_trip() {
+        let data: Vec<u8> = (0..5000usize)
+            .map(|i| if (i / 300) % 2 == 0 { (i * 31 % 251) as u8 } else { 9 })
+            .collect();
+        assert_eq!(unrle(&rle(&data)), data);
+        assert_eq!(unpredict(&predict(&data)), data);
+        assert_eq!(unpredict(&predict(&data[..4999])), &data[..4999]);
+    }
+
+    #[test]
+    fn written_files_decode() {
+        let image = test_image(23, 37);
+        for &pixel_type in [PixelType::Half, PixelType::Float].iter() {
+            for &compression in [Compression::None, Compression::Rle, Compression::Zips, Compression::Zip].iter() {
+                let decoded = round_trip(&image, pixel_type, compression);
+                assert_eq!((decoded.width, decoded.height), (23, 37));
+                let names: Vec<&str> = decoded.channels.iter().map(|c| c.0.as_str()).collect();
+                assert_eq!(names, ["B", "G", "R"]);
+
+                for (c, name) in ["R", "G", "B"].iter().enumerate() {
+                    for (i, v) in decoded.values[*name].iter().enumerate() {
+                        let expected = image.data[i * 3 + c];
+                        let expected = match pixel_type {
+                            PixelType::Half => f16_to_f32(f32_to_f16(expected)),
+                            PixelType::Float => expected
+                        };
+                        assert_eq!(*v, expected, "{:?} {:?} {} at {}", pixel_type, compression, name, i);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exact_aovs_stay_float() {
+        let beauty = test_image(4, 3);
+        let ids = HdrImage::from_fn(4, 3, 1, ColorSpace::Raw, |i| vec![4097.0 + i as f64]);
+        let framebuffer = Framebuffer {
+            beauty,
+            aovs: vec![Aov { name: "object_id", channels: &["id"], image: ids, exact: true }]
+        };
+
+        let path = std::env::temp_dir().join(format!("exr-test-{}-ids.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_framebuffer(path, &framebuffer, PixelType::Half, Compression::Zip).unwrap();
+        let decoded = decode(&std::fs::read(path).unwrap());
+        std::fs::remove_file(path).unwrap();
+
+        let types: HashMap<&str, i32> = decoded.channels.iter().map(|(n, t)| (n.as_str(), *t)).collect();
+        assert_eq!(types["R"], 1);
+        assert_eq!(types["object_id.id"], 2);
+        for (i, v) in decoded.values["object_id.id"].iter().enumerate() {
+            assert_eq!(*v, 4097.0 + i as f32);
+        }
+    }
+}
@@ -1,12 +1,29 @@
-use cgmath::{Point3, InnerSpace};
+use cgmath::{Point3, Vector3, InnerSpace, vec3};
+use rand::Rng;
+use std::f64::consts::PI;
 
 use crate::animation::Animated;
 use crate::raytracing::Ray;
 use crate::hittable::{HitRecord, Hittable};
 use crate::material::Material;
+use crate::util::{Onb, random_unit_vec};
 use std::sync::Arc;
 
 
+fn sphere_uv(p: &Vector3<f64>) -> (f64, f64) {
+    let theta = (-p.y).acos();
+    let phi = (-p.z).atan2(p.x) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+
+// Direction of increasing u, zero at the poles
+fn sphere_tangent(n: &Vector3<f64>) -> Vector3<f64> {
+    let t = vec3(-n.z, 0.0, n.x);
+    if t.magnitude2() > 0.0 { t.normalize() } else { t }
+}
+
+
 pub struct Sphere {
     pub center: Point3<f64>,
     pub radius: f64,
@@ -15,7 +32,7 @@ pub struct Sphere {
 
 impl Hittable for Sphere {
 
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
         let oc = r.origin - self.center;
         let a = r.dir.magnitude2();
         let half_b = oc.dot(r.dir);
@@ -36,10 +53,65 @@ impl Hittable for Sphere {
         }
 
         rec.p = r.at(root);
-        rec.material = Some(self.mat.clone());
+        rec.material = Some(self.mat.as_ref());
         rec.t = root;
         let outward_normal = (rec.p - self.center) / self.radius;
         rec.set_face_normal(r, &outward_normal);
+        let (u, v) = sphere_uv(&outward_normal);
+        rec.u = u;
+        rec.v = v;
+        rec.tangent = sphere_tangent(&outward_normal);
+        true
+    }
+
+    fn pdf_value(&self, origin: &Point3<f64>, dir: &Vector3<f64>) -> f64 {
+        let mut rec = HitRecord::new();
+        if !self.hit(&Ray { origin: *origin, dir: *dir, wavelengths: None, channel: None }, 0.001, std::f64::INFINITY, &mut rec) {
+            return 0.0
+        }
+
+        let distance_squared = (self.center - origin).magnitude2();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return 1.0 / (4.0 * PI)
+        }
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+    }
+
+    // Uniform over the cone of directions subtended by the sphere
+    fn random(&self, origin: &Point3<f64>) -> Vector3<f64> {
+        let direction = self.center - origin;
+        let distance_squared = direction.magnitude2();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return random_unit_vec()
+        }
+
+        let r1: f64 = rand::thread_rng().gen();
+        let r2: f64 = rand::thread_rng().gen();
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        let z = 1.0 + r2 * (cos_theta_max - 1.0);
+        let phi = 2.0 * PI * r1;
+        let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+
+        Onb::from_w(direction).local(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface<'a>(&'a self, rec: &mut HitRecord<'a>) -> bool {
+        let outward_normal = random_unit_vec() * self.radius.signum();
+        rec.p = self.center + outward_normal * self.radius.abs();
+        rec.normal = outward_normal;
+        rec.front_face = true;
+        rec.material = Some(self.mat.as_ref());
+        let (u, v) = sphere_uv(&outward_normal);
+        rec.u = u;
+        rec.v = v;
+        rec.tangent = sphere_tangent(&outward_normal);
         true
     }
 
@@ -73,10 +145,26 @@ impl AnimatedSphere {
 
 impl Hittable for AnimatedSphere {
 
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
         self.sphere.hit(r, t_min, t_max, rec)
     }
 
+    fn pdf_value(&self, origin: &Point3<f64>, dir: &Vector3<f64>) -> f64 {
+        self.sphere.pdf_value(origin, dir)
+    }
+
+    fn random(&self, origin: &Point3<f64>) -> Vector3<f64> {
+        self.sphere.random(origin)
+    }
+
+    fn area(&self) -> f64 {
+        self.sphere.area()
+    }
+
+    fn sample_surface<'a>(&'a self, rec: &mut HitRecord<'a>) -> bool {
+        self.sphere.sample_surface(rec)
+    }
+
 }
 
 impl Animated for AnimatedSphere {
@@ -88,3 +176,98 @@ impl Animated for AnimatedSphere {
 
 }
 
+
+// Parallelogram spanned by `u` and `v` from the corner `q`
+pub struct Quad {
+    q: Point3<f64>,
+    u: Vector3<f64>,
+    v: Vector3<f64>,
+    w: Vector3<f64>,
+    normal: Vector3<f64>,
+    d: f64,
+    area: f64,
+    mat: Arc<dyn Material>
+}
+
+impl Quad {
+
+    pub fn new(q: Point3<f64>, u: Vector3<f64>, v: Vector3<f64>, mat: Arc<dyn Material>) -> Self {
+        let n = u.cross(v);
+        let normal = n.normalize();
+        let d = normal.dot(vec3(q.x, q.y, q.z));
+        let w = n / n.dot(n);
+        Quad { q, u, v, w, normal, d, area: n.magnitude(), mat }
+    }
+
+}
+
+impl Hittable for Quad {
+
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        let denom = self.normal.dot(r.dir);
+        if denom.abs() < 1e-8 {
+            return false
+        }
+
+        let t = (self.d - self.normal.dot(vec3(r.origin.x, r.origin.y, r.origin.z))) / denom;
+        if t < t_min || t > t_max {
+            return false
+        }
+
+        let p = r.at(t);
+        let planar = p - self.q;
+        let alpha = self.w.dot(planar.cross(self.v));
+        let beta = self.w.dot(self.u.cross(planar));
+        if alpha < 0.0 || alpha > 1.0 || beta < 0.0 || beta > 1.0 {
+            return false
+        }
+
+        rec.t = t;
+        rec.p = p;
+        rec.u = alpha;
+        rec.v = beta;
+        rec.tangent = self.u.normalize();
+        rec.material = Some(self.mat.as_ref());
+        rec.set_face_normal(r, &self.normal);
+        true
+    }
+
+    fn pdf_value(&self, origin: &Point3<f64>, dir: &Vector3<f64>) -> f64 {
+        let mut rec = HitRecord::new();
+        if !self.hit(&Ray { origin: *origin, dir: *dir, wavelengths: None, channel: None }, 0.001, std::f64::INFINITY, &mut rec) {
+            return 0.0
+        }
+
+        let distance_squared = rec.t * rec.t * dir.magnitude2();
+        let cosine = (dir.dot(rec.normal) / dir.magnitude()).abs();
+        distance_squared / (cosine * self.area)
+    }
+
+    fn random(&self, origin: &Point3<f64>) -> Vector3<f64> {
+        let mut rng = rand::thread_rng();
+        let p = self.q + self.u * rng.gen::<f64>() + self.v * rng.gen::<f64>();
+        p - origin
+    }
+
+    fn area(&self) -> f64 {
+        self.area
+    }
+
+    fn sample_surface<'a>(&'a self, rec: &mut HitRecord<'a>) -> bool {
+        let mut rng = rand::thread_rng();
+        rec.u = rng.gen();
+        rec.v = rng.gen();
+        rec.p = self.q + self.u * rec.u + self.v * rec.v;
+        rec.normal = self.normal;
+        rec.front_face = true;
+        rec.material = Some(self.mat.as_ref());
+        true
+    }
+
+}
+
+impl Animated for Quad {
+
+    fn update(&mut self, _time: f64) {}
+
+}
@@ -0,0 +1,59 @@
+use prisma::Rgb;
+
+use crate::color::ColorSpace;
+use crate::tonemap::ToneMapper;
+
+
+// Linear floating point image, `channels` interleaved values per pixel in rows from
+// the top, with color channels in `space`
+#[derive(Clone)]
+pub struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub space: ColorSpace,
+    pub data: Vec<f32>
+}
+
+impl HdrImage {
+
+    pub fn from_fn<F>(width: usize, height: usize, channels: usize, space: ColorSpace, f: F) -> Self
+        where F: Fn(usize) -> Vec<f64> {
+        let data = (0..width * height)
+            .flat_map(|i| f(i).into_iter().map(|v| v as f32))
+            .collect();
+        HdrImage { width, height, channels, space, data }
+    }
+
+    // Color of pixel `i` in scanline order, a single channel is taken as gray
+    pub fn rgb(&self, i: usize) -> Rgb<f64> {
+        let p = &self.data[i * self.channels..(i + 1) * self.channels];
+        match p.len() {
+            1 | 2 => Rgb::new(p[0] as f64, p[0] as f64, p[0] as f64),
+            _ => Rgb::new(p[0] as f64, p[1] as f64, p[2] as f64)
+        }
+    }
+
+    // Color of pixel `i` in the Rec. 709 primaries of sRGB displays
+    fn display_rgb(&self, i: usize) -> Rgb<f64> {
+        self.space.convert(&self.rgb(i), ColorSpace::LinearSrgb)
+    }
+
+    // Tone mapped, packed as 0RGB for display in a window
+    pub fn to_display(&self, tone_mapper: &ToneMapper) -> Vec<u32> {
+        (0..self.width * self.height)
+            .map(|i| {
+                let [r, g, b] = tone_mapper.to_rgb8(&self.display_rgb(i));
+                ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+            })
+            .collect()
+    }
+
+    // Tone mapped 8-bit RGB, for export to LDR formats
+    pub fn to_rgb8(&self, tone_mapper: &ToneMapper) -> Vec<u8> {
+        (0..self.width * self.height)
+            .flat_map(|i| tone_mapper.to_rgb8(&self.display_rgb(i)).to_vec())
+            .collect()
+    }
+
+}
@@ -0,0 +1,242 @@
+use cgmath::{Point3, Vector3, InnerSpace, point3, vec3};
+use image::ImageError;
+use image::error::{ParameterError, ParameterErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::animation::Animated;
+use crate::raytracing::Ray;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Material;
+
+
+// Regular grid of height samples spanning `size_x` by `size_z` from `origin`,
+// rows of the source image running along +z
+pub struct Heightfield {
+    origin: Point3<f64>,
+    size_x: f64,
+    size_z: f64,
+    nx: usize,
+    nz: usize,
+    heights: Vec<f64>,
+    normals: Vec<Vector3<f64>>,
+    bounds: Aabb,
+    mat: Arc<dyn Material>
+}
+
+impl Heightfield {
+
+    pub fn new(heights: Vec<f64>, nx: usize, nz: usize, origin: Point3<f64>, size_x: f64,
+               size_z: f64, mat: Arc<dyn Material>) -> Self {
+        assert!(nx >= 2 && nz >= 2, "heightfield needs at least 2x2 samples");
+        assert_eq!(heights.len(), nx * nz, "heightfield size does not match its dimensions");
+
+        let (min_h, max_h) = heights.iter()
+            .fold((std::f64::INFINITY, -std::f64::INFINITY), |(lo, hi), &h| (lo.min(h), hi.max(h)));
+        let bounds = Aabb::new(
+            point3(origin.x, origin.y + min_h, origin.z),
+            point3(origin.x + size_x, origin.y + max_h, origin.z + size_z)
+        );
+
+        let mut heightfield = Heightfield {
+            origin, size_x, size_z, nx, nz, heights, normals: Vec::new(), bounds, mat
+        };
+        heightfield.normals = heightfield.vertex_normals();
+        heightfield
+    }
+
+    pub fn from_image<P: AsRef<Path>>(path: P, origin: Point3<f64>, size_x: f64, size_z: f64,
+                                      height_scale: f64, mat: Arc<dyn Material>)
+        -> Result<Self, ImageError> {
+
+        let image = image::open(path)?.to_luma16();
+        let (width, height) = image.dimensions();
+        if width < 2 || height < 2 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)))
+        }
+        let heights = image.pixels()
+            .map(|p| p[0] as f64 / std::u16::MAX as f64 * height_scale)
+            .collect();
+
+        Ok(Heightfield::new(heights, width as usize, height as usize, origin, size_x, size_z, mat))
+    }
+
+    fn cell_size(&self) -> (f64, f64) {
+        (self.size_x / (self.nx - 1) as f64, self.size_z / (self.nz - 1) as f64)
+    }
+
+    fn height(&self, i: usize, j: usize) -> f64 {
+        self.heights[j * self.nx + i]
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Point3<f64> {
+        let (dx, dz) = self.cell_size();
+        point3(
+            self.origin.x + i as f64 * dx,
+            self.origin.y + self.height(i, j),
+            self.origin.z + j as f64 * dz
+        )
+    }
+
+    // Central differences of the height samples, one-sided on the borders
+    fn vertex_normals(&self) -> Vec<Vector3<f64>> {
+        let (dx, dz) = self.cell_size();
+        let mut normals = Vec::with_capacity(self.nx * self.nz);
+        for j in 0..self.nz {
+            for i in 0..self.nx {
+                let (i0, i1) = (i.saturating_sub(1), (i + 1).min(self.nx - 1));
+                let (j0, j1) = (j.saturating_sub(1), (j + 1).min(self.nz - 1));
+                let slope_x = (self.height(i1, j) - self.height(i0, j)) / ((i1 - i0) as f64 * dx);
+                let slope_z = (self.height(i, j1) - self.height(i, j0)) / ((j1 - j0) as f64 * dz);
+                normals.push(vec3(-slope_x, 1.0, -slope_z).normalize());
+            }
+        }
+        normals
+    }
+
+    // Möller-Trumbore, returning the distance and barycentrics of b and c
+    fn hit_triangle(r: &Ray, a: Point3<f64>, b: Point3<f64>, c: Point3<f64>, t_min: f64,
+                    t_max: f64) -> Option<(f64, f64, f64)> {
+        let e1 = b - a;
+        let e2 = c - a;
+        let p = r.dir.cross(e2);
+        let det = e1.dot(p);
+        if det.abs() < 1e-12 {
+            return None
+        }
+
+        let inv_det = 1.0 / det;
+        let s = r.origin - a;
+        let beta = s.dot(p) * inv_det;
+        if beta < 0.0 || beta > 1.0 {
+            return None
+        }
+        let q = s.cross(e1);
+        let gamma = r.dir.dot(q) * inv_det;
+        if gamma < 0.0 || beta + gamma > 1.0 {
+            return None
+        }
+
+        let t = e2.dot(q) * inv_det;
+        if t < t_min || t > t_max {
+            return None
+        }
+        Some((t, beta, gamma))
+    }
+
+    fn hit_cell<'a>(&'a self, r: &Ray, i: usize, j: usize, t_min: f64, t_max: f64,
+                    rec: &mut HitRecord<'a>) -> bool {
+
+        // Skip cells whose height range the ray segment does not overlap
+        let corners = [(i, j), (i + 1, j), (i, j + 1), (i + 1, j + 1)];
+        let (lo, hi) = corners.iter()
+            .map(|&(ci, cj)| self.origin.y + self.height(ci, cj))
+            .fold((std::f64::INFINITY, -std::f64::INFINITY), |(lo, hi), h| (lo.min(h), hi.max(h)));
+        let y0 = r.at(t_min).y;
+        let y1 = r.at(t_max).y;
+        if y0.min(y1) > hi || y0.max(y1) < lo {
+            return false
+        }
+
+        let triangles = [
+            [(i, j), (i + 1, j), (i + 1, j + 1)],
+            [(i, j), (i + 1, j + 1), (i, j + 1)]
+        ];
+
+        let mut closest: Option<(f64, [(usize, usize); 3], f64, f64)> = None;
+        for tri in triangles.iter() {
+            let limit = closest.map_or(t_max, |c| c.0);
+            let (a, b, c) = (self.vertex(tri[0].0, tri[0].1), self.vertex(tri[1].0, tri[1].1),
+                             self.vertex(tri[2].0, tri[2].1));
+            if let Some((t, beta, gamma)) = Heightfield::hit_triangle(r, a, b, c, t_min, limit) {
+                closest = Some((t, *tri, beta, gamma));
+            }
+        }
+
+        let (t, tri, beta, gamma) = match closest {
+            Some(hit) => hit,
+            None => return false
+        };
+
+        let alpha = 1.0 - beta - gamma;
+        let normal_at = |(vi, vj): (usize, usize)| self.normals[vj * self.nx + vi];
+        let outward_normal = (normal_at(tri[0]) * alpha + normal_at(tri[1]) * beta
+            + normal_at(tri[2]) * gamma).normalize();
+
+        rec.t = t;
+        rec.p = r.at(t);
+        rec.u = (rec.p.x - self.origin.x) / self.size_x;
+        rec.v = 1.0 - (rec.p.z - self.origin.z) / self.size_z;
+        rec.material = Some(self.mat.as_ref());
+        rec.set_face_normal(r, &outward_normal);
+        true
+    }
+
+}
+
+impl Hittable for Heightfield {
+
+    // 2D DDA over the grid cells in xz, front to back
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        let (t_enter, t_exit) = match self.bounds.intersect(r, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false
+        };
+
+        let (dx, dz) = self.cell_size();
+        let entry = r.at(t_enter);
+        let cell = |x: f64, size: f64, n: usize| {
+            ((x / size).floor().max(0.0) as usize).min(n - 2)
+        };
+        let mut i = cell(entry.x - self.origin.x, dx, self.nx);
+        let mut j = cell(entry.z - self.origin.z, dz, self.nz);
+
+        let axis = |dir: f64, origin: f64, grid_origin: f64, index: usize, size: f64| {
+            if dir > 0.0 {
+                (1, (grid_origin + (index + 1) as f64 * size - origin) / dir, size / dir)
+            } else if dir < 0.0 {
+                (-1, (grid_origin + index as f64 * size - origin) / dir, -size / dir)
+            } else {
+                (0, std::f64::INFINITY, std::f64::INFINITY)
+            }
+        };
+        let (step_i, mut next_x, delta_x) = axis(r.dir.x, r.origin.x, self.origin.x, i, dx);
+        let (step_j, mut next_z, delta_z) = axis(r.dir.z, r.origin.z, self.origin.z, j, dz);
+
+        let mut t = t_enter;
+        loop {
+            let t_cell_exit = next_x.min(next_z).min(t_exit);
+            // Pad the cell interval so hits exactly on a cell border are not lost
+            let t_cell_limit = (t_cell_exit + 1e-9 * t_cell_exit.abs().max(1.0)).min(t_exit);
+            if self.hit_cell(r, i, j, t, t_cell_limit, rec) {
+                return true
+            }
+            if t_cell_exit >= t_exit {
+                return false
+            }
+
+            t = t_cell_exit;
+            if next_x < next_z {
+                if (step_i < 0 && i == 0) || (step_i > 0 && i == self.nx - 2) {
+                    return false
+                }
+                i = (i as i64 + step_i) as usize;
+                next_x += delta_x;
+            } else {
+                if (step_j < 0 && j == 0) || (step_j > 0 && j == self.nz - 2) {
+                    return false
+                }
+                j = (j as i64 + step_j) as usize;
+                next_z += delta_z;
+            }
+        }
+    }
+
+}
+
+impl Animated for Heightfield {
+
+    fn update(&mut self, _time: f64) {}
+
+}
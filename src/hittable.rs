@@ -1,27 +1,34 @@
-use std::sync::Arc;
 use cgmath::{Point3, Vector3, InnerSpace, point3, vec3 };
 
 use crate::raytracing::Ray;
 use crate::material::Material;
 
 
-#[derive(Clone)]
-pub struct HitRecord {
+#[derive(Clone, Copy)]
+pub struct HitRecord<'a> {
     pub p: Point3<f64>,
     pub normal: Vector3<f64>,
-    pub material: Option<Arc<dyn Material>>,
+    pub tangent: Vector3<f64>,
+    pub material: Option<&'a dyn Material>,
     pub t: f64,
+    pub object_id: usize,
+    pub u: f64,
+    pub v: f64,
     pub front_face: bool
 }
 
-impl HitRecord {
+impl<'a> HitRecord<'a> {
 
     pub fn new() -> Self {
         HitRecord {
             p: point3(0.0, 0.0, 0.0),
             normal: vec3(0.0, 0.0, 0.0),
+            tangent: vec3(0.0, 0.0, 0.0),
             material: None,
             t: 0.0,
+            object_id: 0,
+            u: 0.0,
+            v: 0.0,
             front_face: false
         }
     }
@@ -35,7 +42,28 @@ impl HitRecord {
 
 
 pub trait Hittable : Send + Sync {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool;
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool;
+
+    // Solid angle density of `random` sampling `dir` from `origin`, for hittables
+    // that can be sampled as lights
+    fn pdf_value(&self, _origin: &Point3<f64>, _dir: &Vector3<f64>) -> f64 {
+        0.0
+    }
+
+    fn random(&self, _origin: &Point3<f64>) -> Vector3<f64> {
+        vec3(1.0, 0.0, 0.0)
+    }
+
+    // Surface area, zero for hittables that cannot be sampled by `sample_surface`
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    // Fills `rec` with a uniformly distributed point on the surface, its outward
+    // normal and material
+    fn sample_surface<'a>(&'a self, _rec: &mut HitRecord<'a>) -> bool {
+        false
+    }
 }
 
 
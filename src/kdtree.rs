@@ -0,0 +1,177 @@
+use cgmath::{Point3, MetricSpace};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+
+// Balanced kd-tree stored implicitly in one array: the median of every range is the
+// root of its subtree, split along the axis of largest extent
+pub struct KdTree<T> {
+    nodes: Vec<(Point3<f64>, T)>,
+    axes: Vec<usize>
+}
+
+impl<T> KdTree<T> {
+
+    pub fn new(items: Vec<(Point3<f64>, T)>) -> Self {
+        let mut nodes = items;
+        let mut axes = vec![0; nodes.len()];
+        build(&mut nodes, &mut axes);
+        KdTree { nodes, axes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Up to `k` items closest to `p` and no farther than `max_dist`, along with their
+    // squared distances, in no particular order
+    pub fn nearest(&self, p: Point3<f64>, k: usize, max_dist: f64) -> Vec<(f64, &T)> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        let mut max_dist2 = max_dist * max_dist;
+        if k > 0 {
+            self.search(0, self.nodes.len(), p, k, &mut max_dist2, &mut heap);
+        }
+        heap.into_iter().map(|n| (n.dist2, n.item)).collect()
+    }
+
+    fn search<'a>(&'a self, lo: usize, hi: usize, p: Point3<f64>, k: usize, max_dist2: &mut f64,
+                  heap: &mut BinaryHeap<Neighbour<'a, T>>) {
+        if lo >= hi {
+            return
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let (q, item) = &self.nodes[mid];
+        let axis = self.axes[mid];
+        let delta = p[axis] - q[axis];
+        let (near, far) = if delta < 0.0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+
+        self.search(near.0, near.1, p, k, max_dist2, heap);
+
+        let dist2 = p.distance2(*q);
+        if dist2 < *max_dist2 {
+            heap.push(Neighbour { dist2, item });
+            if heap.len() > k {
+                heap.pop();
+            }
+            if heap.len() == k {
+                *max_dist2 = heap.peek().unwrap().dist2;
+            }
+        }
+
+        if delta * delta < *max_dist2 {
+            self.search(far.0, far.1, p, k, max_dist2, heap);
+        }
+    }
+
+}
+
+
+fn build<T>(nodes: &mut [(Point3<f64>, T)], axes: &mut [usize]) {
+    if nodes.len() <= 1 {
+        return
+    }
+
+    let mut min = nodes[0].0;
+    let mut max = nodes[0].0;
+    for (p, _) in nodes.iter() {
+        for a in 0..3 {
+            min[a] = min[a].min(p[a]);
+            max[a] = max[a].max(p[a]);
+        }
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+
+    let mid = nodes.len() / 2;
+    nodes.select_nth_unstable_by(mid, |a, b| a.0[axis].partial_cmp(&b.0[axis]).unwrap_or(Ordering::Equal));
+    axes[mid] = axis;
+
+    let (left, right) = nodes.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+
+// Max-heap entry ordered by distance, so that the farthest of the current neighbours
+// is the one dropped
+struct Neighbour<'a, T> {
+    dist2: f64,
+    item: &'a T
+}
+
+impl<'a, T> PartialEq for Neighbour<'a, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist2 == other.dist2
+    }
+}
+
+impl<'a, T> Eq for Neighbour<'a, T> {}
+
+impl<'a, T> PartialOrd for Neighbour<'a, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, T> Ord for Neighbour<'a, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist2.partial_cmp(&other.dist2).unwrap_or(Ordering::Equal)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::point3;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_point(rng: &mut StdRng) -> Point3<f64> {
+        point3(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0))
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let points: Vec<Point3<f64>> = (0..2000).map(|_| random_point(&mut rng)).collect();
+        let tree = KdTree::new(points.iter().copied().enumerate().map(|(i, p)| (p, i)).collect());
+
+        for &(k, max_dist) in [(1, 10.0), (8, 10.0), (50, 0.3), (50, 0.05), (3000, 0.5)].iter() {
+            for _ in 0..50 {
+                let p = random_point(&mut rng);
+
+                let mut expected: Vec<(f64, usize)> = points.iter().enumerate()
+                    .map(|(i, q)| (p.distance2(*q), i))
+                    .filter(|(d, _)| *d < max_dist * max_dist)
+                    .collect();
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                expected.truncate(k);
+
+                let mut found: Vec<(f64, usize)> = tree.nearest(p, k, max_dist).into_iter()
+                    .map(|(d, &i)| (d, i))
+                    .collect();
+                found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_handles_empty_trees_and_zero_k() {
+        let empty: KdTree<usize> = KdTree::new(Vec::new());
+        assert!(empty.nearest(point3(0.0, 0.0, 0.0), 4, 1.0).is_empty());
+
+        let tree = KdTree::new(vec![(point3(0.0, 0.0, 0.0), 0)]);
+        assert!(tree.nearest(point3(0.0, 0.0, 0.0), 0, 1.0).is_empty());
+    }
+}
@@ -1,26 +1,48 @@
-use std::io::{Error};
+use std::io::{Error, ErrorKind};
 use std::{thread, time};
-use std::iter::once;
 use cgmath::{point3, vec3};
 use minifb::{Key, Window, WindowOptions};
 
 
+mod aabb;
 mod animation;
+mod aov;
+mod bdpt;
 mod camera;
+mod coated;
+mod color;
+mod curve;
+mod exr;
 mod geometry;
+mod hdr;
+mod heightfield;
 mod hittable;
+mod kdtree;
 mod material;
+mod microfacet;
+mod pdf;
+mod photon;
+mod principled;
 mod raytracing;
 mod rendering;
 mod scene;
+mod sdf;
+mod spectrum;
+mod texture;
+mod tonemap;
 mod util;
+mod volume;
 
 
 use crate::camera::Camera;
+use crate::color::ColorSpace;
+use crate::exr::{Compression, PixelType};
+use crate::hdr::HdrImage;
 use crate::hittable::Hittable;
-use crate::rendering::{Config, render};
-use crate::scene::random_scene;
-use crate::util::{to_rgb};
+use crate::raytracing::SamplingStrategy;
+use crate::rendering::{Config, Integrator, render};
+use crate::scene::{cloud_scene, coated_scene, diffuse_scene, hair_scene, lights_scene, microfacet_scene, mix_scene, principled_scene, random_scene, sdf_scene, subsurface_scene, terrain_scene, test_scene, volume_scene};
+use crate::tonemap::{Operator, ToneMapper};
 
 
 fn main() -> Result<(), Error> {
@@ -28,6 +50,19 @@ fn main() -> Result<(), Error> {
     // Image
 
     let image_path = "image.png";
+    let exr_path = "image.exr";
+    let exr_pixel_type = env_option("EXR_PIXEL_TYPE", PixelType::Half, |v| match v {
+        "half" => Some(PixelType::Half),
+        "float" => Some(PixelType::Float),
+        _ => None
+    })?;
+    let exr_compression = env_option("EXR_COMPRESSION", Compression::Zip, |v| match v {
+        "none" => Some(Compression::None),
+        "rle" => Some(Compression::Rle),
+        "zips" => Some(Compression::Zips),
+        "zip" => Some(Compression::Zip),
+        _ => None
+    })?;
     let aspect_ratio = 16.0 / 9.0;
     let image_width: usize = 400;
     let image_height: usize = (image_width as f64 / aspect_ratio) as usize;
@@ -36,6 +71,42 @@ fn main() -> Result<(), Error> {
     let time0 = 0.0;
     let time1 = 0.5;
     let max_depth = 50;
+    let rr_min_depth = 5;
+    let photons = 500_000;
+    // Stops of exposure, and the luminance the extended Reinhard operator maps to white
+    let exposure = env_option("EXPOSURE", 0.0, |v| v.parse().ok().filter(|e: &f64| e.is_finite()))?;
+    let white_point = env_option("WHITE_POINT", 4.0, |v| v.parse().ok().filter(|w: &f64| w.is_finite() && *w > 0.0))?;
+    let working_space = env_option("COLORSPACE", ColorSpace::LinearSrgb, |v| match v {
+        "srgb" => Some(ColorSpace::LinearSrgb),
+        "acescg" => Some(ColorSpace::AcesCg),
+        "rec2020" => Some(ColorSpace::Rec2020),
+        _ => None
+    })?;
+    let operator = env_option("TONEMAP", Operator::Clamp, |v| match v {
+        "clamp" => Some(Operator::Clamp),
+        "reinhard" => Some(Operator::Reinhard),
+        "extended" => Some(Operator::ExtendedReinhard { white: white_point }),
+        "aces" => Some(Operator::AcesFilmic),
+        "hable" => Some(Operator::Hable),
+        _ => None
+    })?;
+    let sampling = env_option("SAMPLING", SamplingStrategy::Mis, |v| match v {
+        "bsdf" => Some(SamplingStrategy::Bsdf),
+        "light" => Some(SamplingStrategy::Light),
+        "mis" => Some(SamplingStrategy::Mis),
+        _ => None
+    })?;
+    let integrator = env_option("INTEGRATOR", Integrator::PathTracing, |v| match v {
+        "path" => Some(Integrator::PathTracing),
+        "bdpt" => Some(Integrator::Bidirectional),
+        "photon" => Some(Integrator::PhotonMapping),
+        _ => None
+    })?;
+    let spectral = env_option("SPECTRAL", false, |v| match v {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None
+    })?;
 
     // Window
 
@@ -50,7 +121,26 @@ fn main() -> Result<(), Error> {
 
     // World
 
-    let mut world = random_scene();
+    let mut world = match std::env::args().nth(1).as_deref() {
+        Some("test") => test_scene(working_space),
+        Some("volumes") => volume_scene(working_space),
+        Some("cloud") => cloud_scene(working_space, std::env::args().nth(2).as_deref()),
+        Some("sdf") => sdf_scene(working_space),
+        Some("lights") => lights_scene(working_space),
+        Some("microfacet") => microfacet_scene(working_space),
+        Some("principled") => principled_scene(working_space),
+        Some("coated") => coated_scene(working_space),
+        Some("mix") => mix_scene(working_space),
+        Some("diffuse") => diffuse_scene(working_space),
+        Some("subsurface") => subsurface_scene(working_space),
+        Some("hair") => hair_scene(working_space, std::env::args().nth(2).as_deref()),
+        Some("terrain") => terrain_scene(
+            working_space,
+            std::env::args().nth(2).as_deref(),
+            std::env::args().nth(3).as_deref()
+        ),
+        _ => random_scene(working_space)
+    };
 
     // Camera
 
@@ -75,13 +165,20 @@ fn main() -> Result<(), Error> {
 
     let begin_t = time::Instant::now();
 
-    let buffer = render(&mut world, &camera, time0, time1, &Config::new(
+    let config = Config::new(
         image_width,
         image_height,
         samples_per_pixel,
         time_samples,
         max_depth
-    ));
+    ).with_sampling(sampling)
+        .with_russian_roulette(rr_min_depth)
+        .with_integrator(integrator)
+        .with_photons(photons)
+        .with_spectral(spectral)
+        .with_tone_mapping(exposure, operator);
+    let framebuffer = render(&mut world, &camera, time0, time1, &config);
+    let buffer = framebuffer.beauty.to_display(config.tone_mapper());
 
     let duration = begin_t.elapsed();
 
@@ -91,22 +188,17 @@ fn main() -> Result<(), Error> {
     window.update_with_buffer(&buffer, image_width, image_height)
         .unwrap();
 
-    let rgb_buffer: Vec<u8> = buffer.iter()
-        .map(|x| {
-            to_rgb(*x)
-        })
-        .flat_map(|x| {
-            once(x[0]).chain(once(x[1])).chain(once(x[2]))
-        })
-        .collect();
+    save_png(image_path, &framebuffer.beauty, config.tone_mapper());
+    exr::write_framebuffer(exr_path, &framebuffer, exr_pixel_type, exr_compression)?;
 
-    let image_buffer: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> = image::ImageBuffer::from_raw(
-        image_width as u32,
-        image_height as u32,
-        rgb_buffer
-    ).unwrap();
-
-    image_buffer.save(image_path).unwrap();
+    // Denoising guides, with normals mapped from [-1, 1]
+    for (name, offset, path) in [("albedo", 0.0f32, "albedo.png"), ("normal", 1.0, "normal.png")].iter() {
+        let mut guide = framebuffer.aov(name).unwrap().image.clone();
+        for v in guide.data.iter_mut() {
+            *v = (*v + offset) / (1.0 + offset);
+        }
+        save_png(path, &guide, &ToneMapper::default());
+    }
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
         thread::sleep(time::Duration::from_millis(10));
@@ -117,3 +209,26 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+
+// Value of the environment variable `name` as read by `parse`, `default` if unset
+fn env_option<T, F: Fn(&str) -> Option<T>>(name: &str, default: T, parse: F) -> Result<T, Error> {
+    match std::env::var(name) {
+        Ok(v) => parse(&v).ok_or_else(|| Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unrecognised value for {}: {:?}", name, v)
+        )),
+        Err(_) => Ok(default)
+    }
+}
+
+
+fn save_png(path: &str, image: &HdrImage, tone_mapper: &ToneMapper) {
+    let image_buffer: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> = image::ImageBuffer::from_raw(
+        image.width as u32,
+        image.height as u32,
+        image.to_rgb8(tone_mapper)
+    ).unwrap();
+
+    image_buffer.save(path).unwrap();
+}
@@ -1,10 +1,19 @@
 use cgmath::{Vector3, InnerSpace};
-use prisma::Rgb;
+use prisma::{Rgb, Lerp};
 use rand::Rng;
+use num::clamp;
+use std::f64::consts::PI;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
+use crate::color::{Color, ColorSpace};
 use crate::raytracing::Ray;
 use crate::hittable::HitRecord;
-use crate::util::{random_unit_vec, vec_near_zero, random_vec_in_unit_sphere};
+use crate::pdf::{Pdf, CosinePdf, MixturePdf, PhongPdf, SpherePdf, HenyeyGreensteinPdf, henyey_greenstein};
+use crate::spectrum::{sample_coefficients, sample_rgb, Wavelengths};
+use crate::texture::{SolidColor, Texture};
+use crate::util::{vec_near_zero, scale_color, Onb};
 
 
 fn reflect(v: &Vector3<f64>, n: &Vector3<f64>) -> Vector3<f64> {
@@ -19,6 +28,7 @@ fn refract(v: &Vector3<f64>, n: &Vector3<f64>, ior_ratio: f64) -> Vector3<f64> {
     r_out_perp + r_out_parallel
 }
 
+
 fn schlick_reflectance(cosine: f64, ior: f64) -> f64 {
     let mut r0 = (1.0 - ior) / (1.0 + ior);
     r0 = r0 * r0;
@@ -26,19 +36,84 @@ fn schlick_reflectance(cosine: f64, ior: f64) -> f64 {
 }
 
 
+// Narrows a path down to the hero wavelength, or to a single color channel on RGB
+// paths, before scattering that differs between them. Returns the attenuation making
+// up for the ones dropped.
+pub fn isolate_channel(wavelengths: &mut Option<Wavelengths>, channel: &mut Option<usize>) -> Rgb<f64> {
+    isolate_given_channel(wavelengths, channel, rand::thread_rng().gen_range(0..3))
+}
+
+
+// `isolate_channel` with the channel RGB paths keep, `c`, picked uniformly by the caller
+pub fn isolate_given_channel(wavelengths: &mut Option<Wavelengths>, channel: &mut Option<usize>,
+                             c: usize) -> Rgb<f64> {
+    if let Some(w) = wavelengths.as_mut() {
+        return w.terminate_secondary()
+    }
+    if channel.is_some() {
+        return Rgb::new(1.0, 1.0, 1.0)
+    }
+    let mut weights = [0.0; 3];
+    weights[c] = 3.0;
+    *channel = Some(c);
+    Rgb::new(weights[0], weights[1], weights[2])
+}
+
+
+// Outcome of a scattering event. Specular materials pick the scattered ray
+// themselves and weight it by `attenuation`, all others provide a PDF to sample
+// directions from and evaluate their BSDF through `Material::eval`.
+pub struct ScatterRecord {
+    pub attenuation: Rgb<f64>,
+    pub is_specular: bool,
+    pub specular_ray: Ray,
+    pub pdf: Option<Box<dyn Pdf>>
+}
+
+impl ScatterRecord {
+
+    pub fn new() -> Self {
+        ScatterRecord {
+            attenuation: Rgb::new(0.0, 0.0, 0.0),
+            is_specular: false,
+            specular_ray: Ray::new(),
+            pdf: None
+        }
+    }
+
+}
+
+
 pub trait Material : Send + Sync {
-    fn scatter(&self, r: &Ray, rec: &HitRecord, attenuation: &mut Rgb<f64>,
-               scattered: &mut Ray) -> bool;
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool;
+
+    // BSDF (or phase function) times the cosine term for a non-specular scattered ray
+    fn eval(&self, _r: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Rgb<f64> {
+        Rgb::new(0.0, 0.0, 0.0)
+    }
+
+    fn emitted(&self, _r: &Ray, _rec: &HitRecord) -> Rgb<f64> {
+        Rgb::new(0.0, 0.0, 0.0)
+    }
+
+    // Phase functions scatter inside media, where there is no surface cosine
+    fn is_volumetric(&self) -> bool {
+        false
+    }
 }
 
 
 pub struct Lambertian {
-    albedo: Rgb<f64>
+    albedo: Arc<dyn Texture>
 }
 
 impl Lambertian {
 
-    pub fn new(albedo: Rgb<f64>) -> Self {
+    pub fn new<C: Into<Color>>(albedo: C, space: ColorSpace) -> Self {
+        Lambertian { albedo: Arc::new(SolidColor::new(albedo, space)) }
+    }
+
+    pub fn with_texture(albedo: Arc<dyn Texture>) -> Self {
         Lambertian { albedo }
     }
 
@@ -46,20 +121,140 @@ impl Lambertian {
 
 impl Material for Lambertian {
 
-    fn scatter(&self, r: &Ray, rec: &HitRecord, attenuation: &mut Rgb<f64>,
-               scattered: &mut Ray) -> bool {
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = sample_rgb(&self.albedo.value(rec.u, rec.v, &rec.p), &r.wavelengths);
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(CosinePdf::new(rec.normal)));
+        true
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let cosine = rec.normal.dot(scattered.dir.normalize()).max(0.0);
+        scale_color(&sample_rgb(&self.albedo.value(rec.u, rec.v, &rec.p), &r.wavelengths), cosine / PI)
+    }
+
+}
+
 
-        let mut scatter_direction = rec.normal + random_unit_vec();
+// Rough diffuse surface made of V-shaped Lambertian microfacets, brighter towards
+// the light and flatter looking than `Lambertian`. Uses the qualitative model with
+// `sigma_degrees` the standard deviation of the facet slopes.
+pub struct OrenNayar {
+    albedo: Arc<dyn Texture>,
+    a: f64,
+    b: f64
+}
+
+impl OrenNayar {
 
-        if vec_near_zero(scatter_direction) {
-            scatter_direction = rec.normal;
+    pub fn new<C: Into<Color>>(albedo: C, sigma_degrees: f64, space: ColorSpace) -> Self {
+        let sigma = sigma_degrees.to_radians();
+        let sigma2 = sigma * sigma;
+        OrenNayar {
+            albedo: Arc::new(SolidColor::new(albedo, space)),
+            a: 1.0 - sigma2 / (2.0 * (sigma2 + 0.33)),
+            b: 0.45 * sigma2 / (sigma2 + 0.09)
         }
+    }
 
-        *scattered = Ray { origin: rec.p, dir: scatter_direction};
-        *attenuation = self.albedo;
+}
+
+impl Material for OrenNayar {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = sample_rgb(&self.albedo.value(rec.u, rec.v, &rec.p), &r.wavelengths);
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(CosinePdf::new(rec.normal)));
         true
     }
 
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let wo = -r.dir.normalize();
+        let wi = scattered.dir.normalize();
+        let cos_i = rec.normal.dot(wi);
+        let cos_o = rec.normal.dot(wo).max(0.0);
+        if cos_i <= 0.0 {
+            return Rgb::new(0.0, 0.0, 0.0)
+        }
+
+        let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+        let mut max_cos = 0.0;
+        if sin_i > 1e-4 && sin_o > 1e-4 {
+            let pi = (wi - rec.normal * cos_i) / sin_i;
+            let po = (wo - rec.normal * cos_o) / sin_o;
+            max_cos = pi.dot(po).max(0.0);
+        }
+        // sin(alpha) * tan(beta), with alpha the larger polar angle and beta the smaller
+        let (sin_alpha, tan_beta) = if cos_i > cos_o {
+            (sin_o, sin_i / cos_i)
+        } else {
+            (sin_i, sin_o / cos_o.max(1e-4))
+        };
+
+        let f = (self.a + self.b * max_cos * sin_alpha * tan_beta) * cos_i / PI;
+        scale_color(&sample_rgb(&self.albedo.value(rec.u, rec.v, &rec.p), &r.wavelengths), f)
+    }
+
+}
+
+
+// Thin translucent sheet such as paper or a leaf, scattering diffusely to both
+// sides. Light passing through is not displaced, so the sheet has no thickness.
+pub struct DiffuseTransmission {
+    reflectance: Arc<dyn Texture>,
+    transmittance: Arc<dyn Texture>
+}
+
+impl DiffuseTransmission {
+
+    pub fn new<R: Into<Color>, T: Into<Color>>(reflectance: R, transmittance: T, space: ColorSpace) -> Self {
+        DiffuseTransmission {
+            reflectance: Arc::new(SolidColor::new(reflectance, space)),
+            transmittance: Arc::new(SolidColor::new(transmittance, space))
+        }
+    }
+
+    fn lobes(&self, r: &Ray, rec: &HitRecord) -> (Rgb<f64>, Rgb<f64>) {
+        (
+            sample_rgb(&self.reflectance.value(rec.u, rec.v, &rec.p), &r.wavelengths),
+            sample_rgb(&self.transmittance.value(rec.u, rec.v, &rec.p), &r.wavelengths)
+        )
+    }
+
+}
+
+impl Material for DiffuseTransmission {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let (reflectance, transmittance) = self.lobes(r, rec);
+        let reflected = reflectance.red() + reflectance.green() + reflectance.blue();
+        let transmitted = transmittance.red() + transmittance.green() + transmittance.blue();
+        if reflected + transmitted <= 0.0 {
+            return false
+        }
+
+        // Pick a side in proportion to how much light scatters to it
+        let p = reflected / (reflected + transmitted);
+        srec.attenuation = reflectance;
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(MixturePdf::new(vec![
+            (p, Box::new(CosinePdf::new(rec.normal)) as Box<dyn Pdf>),
+            (1.0 - p, Box::new(CosinePdf::new(-rec.normal)))
+        ])));
+        true
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let (reflectance, transmittance) = self.lobes(r, rec);
+        let cosine = rec.normal.dot(scattered.dir.normalize());
+        if cosine >= 0.0 {
+            scale_color(&reflectance, cosine / PI)
+        } else {
+            scale_color(&transmittance, -cosine / PI)
+        }
+    }
+
 }
 
 
@@ -70,51 +265,169 @@ pub struct Metal {
 
 impl Metal {
 
-    pub fn new(albedo: Rgb<f64>, fuzz: f64) -> Self {
+    pub fn new<C: Into<Color>>(albedo: C, fuzz: f64, space: ColorSpace) -> Self {
         Metal {
-            albedo,
+            albedo: albedo.into().to(space),
             fuzz: if fuzz < 1.0 { fuzz } else { 1.0 }
         }
     }
 
 }
 
+impl Metal {
+
+    // Glossy reflection is a normalized Phong lobe around the mirror direction, so it
+    // can be evaluated for light sampling. The exponent gives about the spread of the
+    // fuzz sphere.
+    fn exponent(&self) -> f64 {
+        2.0 / (self.fuzz * self.fuzz) - 2.0
+    }
+
+}
+
 impl Material for Metal {
 
-    fn scatter(&self, r: &Ray, rec: &HitRecord, attenuation: &mut Rgb<f64>,
-               scattered: &mut Ray) -> bool {
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let reflected = reflect(&r.dir.normalize(), &rec.normal);
+        srec.attenuation = sample_rgb(&self.albedo, &r.wavelengths);
+
+        if self.fuzz <= 0.0 {
+            srec.specular_ray = Ray { origin: rec.p, dir: reflected, wavelengths: r.wavelengths, channel: r.channel };
+            srec.is_specular = true;
+            srec.pdf = None;
+            return reflected.dot(rec.normal) > 0.0
+        }
 
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(PhongPdf::new(reflected, self.exponent())));
+        true
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        if self.fuzz <= 0.0 || scattered.dir.dot(rec.normal) <= 0.0 {
+            return Rgb::new(0.0, 0.0, 0.0)
+        }
         let reflected = reflect(&r.dir.normalize(), &rec.normal);
-        *scattered = Ray {
-            origin: rec.p,
-            dir: reflected + random_vec_in_unit_sphere() * self.fuzz
-        };
-        *attenuation = self.albedo;
-        scattered.dir.dot(rec.normal) > 0.0
+        let lobe = PhongPdf::new(reflected, self.exponent()).value(&scattered.dir);
+        scale_color(&sample_rgb(&self.albedo, &r.wavelengths), lobe)
+    }
+
+}
+
+
+// Wavelengths in nanometers standing for the red, green and blue channels
+const RGB_WAVELENGTHS: [f64; 3] = [630.0, 532.0, 465.0];
+
+
+// Index of refraction as a function of wavelength, with wavelengths in micrometers
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dispersion {
+    // n = a + b / λ²
+    Cauchy { a: f64, b: f64 },
+    // n² = 1 + Σ b λ² / (λ² - c)
+    Sellmeier { b: [f64; 3], c: [f64; 3] }
+}
+
+impl Dispersion {
+
+    // Schott SF11 dense flint glass
+    pub const SF11: Dispersion = Dispersion::Sellmeier {
+        b: [1.737_596_95, 0.313_747_346, 1.898_781_01],
+        c: [0.013_188_707, 0.062_306_814_2, 155.236_29]
+    };
+
+    // Cauchy fit through the index at the Fraunhofer d line and the Abbe number,
+    // (n_d - 1) / (n_F - n_C). Lower Abbe numbers disperse more.
+    pub fn from_abbe(ior: f64, abbe: f64) -> Self {
+        let (d, f, c) = (0.5876, 0.4861, 0.6563);
+        let b = (ior - 1.0) / (abbe * (1.0 / (f * f) - 1.0 / (c * c)));
+        Dispersion::Cauchy { a: ior - b / (d * d), b }
+    }
+
+    pub fn ior(&self, lambda_nm: f64) -> f64 {
+        let l = lambda_nm / 1000.0;
+        let l2 = l * l;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } =>
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt()
+        }
     }
 
 }
 
 
 pub struct Dielectric {
-    ior: f64
+    ior: f64,
+    dispersion: Option<Dispersion>,
+    // Absorption coefficient per unit distance inside, per working space channel
+    absorption: Option<Rgb<f64>>
 }
 
 impl Dielectric {
 
     pub fn new(ior: f64) -> Self {
-        Dielectric { ior }
+        Dielectric { ior, dispersion: None, absorption: None }
+    }
+
+    // Spectral paths refract at the index of their hero wavelength, RGB paths pick one
+    // channel at random and continue with its index
+    pub fn with_dispersion(dispersion: Dispersion) -> Self {
+        Dielectric { ior: dispersion.ior(587.6), dispersion: Some(dispersion), absorption: None }
+    }
+
+    pub fn with_abbe(ior: f64, abbe: f64) -> Self {
+        Dielectric::with_dispersion(Dispersion::from_abbe(ior, abbe))
+    }
+
+    // Beer-Lambert attenuation of light travelling inside, exp(-absorption * distance).
+    // The coefficients are raw values per channel of the working space, in inverse scene
+    // units, and are not converted like colors are. Negative ones count as zero.
+    pub fn with_absorption(mut self, absorption: Rgb<f64>) -> Self {
+        self.absorption = Some(absorption);
+        self
+    }
+
+    // Absorption leaving `color` of the light after travelling `distance` inside
+    pub fn with_transmittance<C: Into<Color>>(self, color: C, distance: f64, space: ColorSpace) -> Self {
+        assert!(distance > 0.0, "transmittance distance must be positive");
+        let c = color.into().to(space);
+        let sigma = |v: f64| -v.max(1e-6).ln() / distance;
+        self.with_absorption(Rgb::new(sigma(c.red()), sigma(c.green()), sigma(c.blue())))
     }
 
 }
 
 impl Material for Dielectric {
 
-    fn scatter(&self, r: &Ray, rec: &HitRecord, attenuation: &mut Rgb<f64>,
-               scattered: &mut Ray) -> bool {
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = Rgb::new(1.0, 1.0, 1.0);
+        srec.is_specular = true;
+        srec.pdf = None;
+
+        let mut wavelengths = r.wavelengths;
+        let mut channel = r.channel;
+        let mut ior = self.ior;
+        if let Some(dispersion) = self.dispersion {
+            srec.attenuation = isolate_channel(&mut wavelengths, &mut channel);
+            ior = match (wavelengths, channel) {
+                (Some(w), _) => dispersion.ior(w.lambda[0]),
+                (None, Some(c)) => dispersion.ior(RGB_WAVELENGTHS[c]),
+                (None, None) => unreachable!()
+            };
+        }
 
-        *attenuation = Rgb::new(1.0, 1.0, 1.0);
-        let refraction_ratio = if rec.front_face { 1.0 / self.ior } else { self.ior };
+        // Back faces are hit from inside, after crossing the medium from the last hit
+        if let (Some(absorption), false) = (self.absorption, rec.front_face) {
+            let sigma = sample_coefficients(&absorption, &r.wavelengths);
+            let d = rec.t * r.dir.magnitude();
+            srec.attenuation = Rgb::new(
+                srec.attenuation.red() * (-sigma.red() * d).exp(),
+                srec.attenuation.green() * (-sigma.green() * d).exp(),
+                srec.attenuation.blue() * (-sigma.blue() * d).exp()
+            );
+        }
+        let refraction_ratio = if rec.front_face { 1.0 / ior } else { ior };
 
         let unit_direction = r.dir.normalize();
         let cos_theta = (-unit_direction).dot(rec.normal).min(1.0);
@@ -129,10 +442,288 @@ impl Material for Dielectric {
             refract(&unit_direction, &rec.normal, refraction_ratio)
         };
 
-        *scattered = Ray { origin: rec.p, dir: direction };
+        srec.specular_ray = Ray { origin: rec.p, dir: direction, wavelengths, channel };
+        true
+    }
+
+}
+
+
+pub struct Isotropic {
+    albedo: Rgb<f64>
+}
+
+impl Isotropic {
+
+    pub fn new<C: Into<Color>>(albedo: C, space: ColorSpace) -> Self {
+        Isotropic { albedo: albedo.into().to(space) }
+    }
+
+}
+
+impl Material for Isotropic {
+
+    fn scatter(&self, r: &Ray, _rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = sample_rgb(&self.albedo, &r.wavelengths);
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(SpherePdf));
+        true
+    }
+
+    fn eval(&self, r: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Rgb<f64> {
+        scale_color(&sample_rgb(&self.albedo, &r.wavelengths), 1.0 / (4.0 * PI))
+    }
+
+    fn is_volumetric(&self) -> bool {
+        true
+    }
+
+}
+
+
+pub struct HenyeyGreenstein {
+    albedo: Rgb<f64>,
+    g: f64
+}
+
+impl HenyeyGreenstein {
+
+    pub fn new<C: Into<Color>>(albedo: C, g: f64, space: ColorSpace) -> Self {
+        HenyeyGreenstein {
+            albedo: albedo.into().to(space),
+            g: clamp(g, -0.99, 0.99)
+        }
+    }
+
+}
+
+impl Material for HenyeyGreenstein {
+
+    fn scatter(&self, r: &Ray, _rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        srec.attenuation = sample_rgb(&self.albedo, &r.wavelengths);
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(HenyeyGreensteinPdf::new(r.dir, self.g)));
         true
     }
 
+    fn eval(&self, r: &Ray, _rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let cos_theta = r.dir.normalize().dot(scattered.dir.normalize());
+        scale_color(&sample_rgb(&self.albedo, &r.wavelengths), henyey_greenstein(cos_theta, self.g))
+    }
+
+    fn is_volumetric(&self) -> bool {
+        true
+    }
+
+}
+
+
+// Stochastic hair fiber model after Marschner et al., sampling one of the R, TT and
+// TRT lobes by its attenuation. The fiber direction comes from the hit tangent.
+pub struct Hair {
+    sigma_a: Rgb<f64>,
+    beta_m: f64,
+    beta_n: f64,
+    alpha: f64,
+    eta: f64
+}
+
+impl Hair {
+
+    pub fn new(sigma_a: Rgb<f64>, beta_m: f64, beta_n: f64, alpha_degrees: f64) -> Self {
+        Hair {
+            sigma_a,
+            beta_m: clamp(beta_m, 0.01, 1.0),
+            beta_n: clamp(beta_n, 0.01, 1.0),
+            alpha: alpha_degrees.to_radians(),
+            eta: 1.55
+        }
+    }
+
+    // Absorption from eumelanin and pheomelanin concentrations
+    pub fn from_melanin(eumelanin: f64, pheomelanin: f64, beta_m: f64, beta_n: f64,
+                        alpha_degrees: f64) -> Self {
+        Hair::new(
+            Rgb::new(
+                eumelanin * 0.419 + pheomelanin * 0.187,
+                eumelanin * 0.697 + pheomelanin * 0.4,
+                eumelanin * 1.37 + pheomelanin * 1.05
+            ),
+            beta_m,
+            beta_n,
+            alpha_degrees
+        )
+    }
+
 }
 
+impl Material for Hair {
 
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let mut rng = rand::thread_rng();
+
+        let wo = -r.dir.normalize();
+        let t = rec.tangent;
+        let n = {
+            let n = rec.normal - t * rec.normal.dot(t);
+            if vec_near_zero(n) { Onb::from_w(t).u } else { n.normalize() }
+        };
+        let b = t.cross(n);
+
+        let sin_theta_o = clamp(wo.dot(t), -1.0, 1.0);
+        let cos_theta_o = (1.0 - sin_theta_o * sin_theta_o).max(0.0).sqrt();
+        let phi_o = wo.dot(b).atan2(wo.dot(n));
+
+        // Offset across the fiber and the refracted ray's geometry inside it
+        let h = clamp(2.0 * rec.v - 1.0, -0.999, 0.999);
+        let gamma_o = h.asin();
+        let eta_p = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o.max(1e-4);
+        let gamma_t = clamp(h / eta_p, -1.0, 1.0).asin();
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).max(1e-4).sqrt();
+
+        let path = 2.0 * gamma_t.cos() / cos_theta_t;
+        let transmittance = Rgb::new(
+            (-self.sigma_a.red() * path).exp(),
+            (-self.sigma_a.green() * path).exp(),
+            (-self.sigma_a.blue() * path).exp()
+        );
+
+        let f = schlick_reflectance(cos_theta_o * gamma_o.cos(), self.eta);
+        let tt = scale_color(&transmittance, (1.0 - f) * (1.0 - f));
+        let trt = Rgb::new(
+            tt.red() * f * transmittance.red(),
+            tt.green() * f * transmittance.green(),
+            tt.blue() * f * transmittance.blue()
+        );
+        let lobes = [Rgb::new(f, f, f), tt, trt];
+
+        let weights: Vec<f64> = lobes.iter()
+            .map(|a| (a.red() + a.green() + a.blue()) / 3.0)
+            .collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return false
+        }
+
+        let mut xi = rng.gen::<f64>() * total;
+        let mut p = 0;
+        while p < 2 && xi >= weights[p] {
+            xi -= weights[p];
+            p += 1;
+        }
+        srec.attenuation = scale_color(&sample_rgb(&lobes[p], &r.wavelengths), total / weights[p]);
+
+        // Longitudinal: mirror around the cuticle-tilted cone with Gaussian roughness
+        let shift = [-2.0 * self.alpha, self.alpha, 4.0 * self.alpha][p];
+        let gaussian = (-2.0 * (1.0 - rng.gen::<f64>()).ln()).sqrt()
+            * (2.0 * PI * rng.gen::<f64>()).cos();
+        let theta_i = -sin_theta_o.asin() + shift + self.beta_m * gaussian;
+
+        // Azimuthal: deflection of the lobe plus logistic roughness
+        let s = self.beta_n * 0.626657069;
+        let xi: f64 = clamp(rng.gen::<f64>(), 1e-6, 1.0 - 1e-6);
+        let phi = 2.0 * p as f64 * gamma_t - 2.0 * gamma_o + p as f64 * PI
+            + s * (xi / (1.0 - xi)).ln();
+        let phi_i = phi_o + phi;
+
+        let dir = t * theta_i.sin() + (n * phi_i.cos() + b * phi_i.sin()) * theta_i.cos();
+        // Only sampled, never evaluated, so handled like a specular lobe
+        srec.specular_ray = Ray { origin: rec.p, dir, wavelengths: r.wavelengths, channel: r.channel };
+        srec.is_specular = true;
+        srec.pdf = None;
+        true
+    }
+
+}
+
+
+// Blends two materials by a constant weight or a grayscale mask, picking one of them
+// at each hit. The pick is hashed from the hit point rather than drawn at random, so
+// every query at a vertex, whichever direction it comes from, sees the same material.
+pub struct Mix {
+    a: Arc<dyn Material>,
+    b: Arc<dyn Material>,
+    // Fraction of `b`, read from the average of the channels
+    mask: Arc<dyn Texture>
+}
+
+impl Mix {
+
+    pub fn new(a: Arc<dyn Material>, b: Arc<dyn Material>, weight: f64) -> Self {
+        Mix { a, b, mask: Arc::new(SolidColor::new(Rgb::new(weight, weight, weight), ColorSpace::Raw)) }
+    }
+
+    pub fn with_mask(a: Arc<dyn Material>, b: Arc<dyn Material>, mask: Arc<dyn Texture>) -> Self {
+        Mix { a, b, mask }
+    }
+
+    fn weight(&self, rec: &HitRecord) -> f64 {
+        let m = self.mask.value(rec.u, rec.v, &rec.p);
+        clamp((m.red() + m.green() + m.blue()) / 3.0, 0.0, 1.0)
+    }
+
+    fn choose(&self, rec: &HitRecord) -> &dyn Material {
+        let mut hasher = DefaultHasher::new();
+        for x in [rec.p.x, rec.p.y, rec.p.z].iter() {
+            x.to_bits().hash(&mut hasher);
+        }
+        let u = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+
+        if u < self.weight(rec) { self.b.as_ref() } else { self.a.as_ref() }
+    }
+
+}
+
+impl Material for Mix {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        self.choose(rec).scatter(r, rec, srec)
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        self.choose(rec).eval(r, rec, scattered)
+    }
+
+    // Emission is blended exactly, it is looked up from many directions at once
+    fn emitted(&self, r: &Ray, rec: &HitRecord) -> Rgb<f64> {
+        let weight = self.weight(rec);
+        let a = self.a.emitted(r, rec);
+        let b = self.b.emitted(r, rec);
+        a.lerp(&b, weight)
+    }
+
+    fn is_volumetric(&self) -> bool {
+        self.a.is_volumetric() && self.b.is_volumetric()
+    }
+
+}
+
+
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>
+}
+
+impl DiffuseLight {
+
+    pub fn new<C: Into<Color>>(emit: C, space: ColorSpace) -> Self {
+        DiffuseLight { emit: Arc::new(SolidColor::new(emit, space)) }
+    }
+
+}
+
+impl Material for DiffuseLight {
+
+    fn scatter(&self, _r: &Ray, _rec: &HitRecord, _srec: &mut ScatterRecord) -> bool {
+        false
+    }
+
+    fn emitted(&self, r: &Ray, rec: &HitRecord) -> Rgb<f64> {
+        if rec.front_face {
+            sample_rgb(&self.emit.value(rec.u, rec.v, &rec.p), &r.wavelengths)
+        } else {
+            Rgb::new(0.0, 0.0, 0.0)
+        }
+    }
+
+}
@@ -0,0 +1,428 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use prisma::Rgb;
+use rand::Rng;
+use std::f64::consts::PI;
+
+use crate::hittable::HitRecord;
+use crate::material::{Material, ScatterRecord};
+use crate::pdf::Pdf;
+use crate::raytracing::Ray;
+use crate::spectrum::sample_coefficients;
+use crate::util::{Onb, vec_near_zero};
+
+
+// Surfaces with a smaller alpha are handled as perfectly smooth, with delta lobes
+const SMOOTH_ALPHA: f64 = 1e-3;
+
+
+// Frame with the shading normal as w and, where the geometry provides one, the
+// tangent as u, which is the direction anisotropic roughness is measured along
+pub fn shading_frame(rec: &HitRecord) -> Onb {
+    let w = rec.normal;
+    let t = rec.tangent - w * rec.tangent.dot(w);
+    if vec_near_zero(t) {
+        return Onb::from_w(w)
+    }
+    let u = t.normalize();
+    Onb { u, v: w.cross(u), w }
+}
+
+
+pub fn to_local(frame: &Onb, d: &Vector3<f64>) -> Vector3<f64> {
+    vec3(d.dot(frame.u), d.dot(frame.v), d.dot(frame.w))
+}
+
+
+pub fn reflect(wo: &Vector3<f64>, n: &Vector3<f64>) -> Vector3<f64> {
+    -*wo + n * (2.0 * wo.dot(*n))
+}
+
+
+// Direction refracted from `wi` through a surface with normal `n` on its side, `eta`
+// being the index ratio of the far side over the near one
+fn refract(wi: &Vector3<f64>, n: &Vector3<f64>, eta: f64) -> Option<Vector3<f64>> {
+    let cos_i = n.dot(*wi);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*wi / eta + n * (cos_i / eta - cos_t))
+}
+
+
+// Unpolarized reflectance of a dielectric interface, `eta` as in `refract`
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let (cos_i, eta) = if cos_i < 0.0 { (-cos_i, 1.0 / eta) } else { (cos_i.min(1.0), eta) };
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+
+// Unpolarized reflectance of a conductor with the complex index `eta` + i `k`
+pub fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = (cos_i * cos_i).min(1.0);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * a * cos_i;
+    let r_s = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+    (r_s + r_p) / 2.0
+}
+
+
+fn fresnel_conductor_rgb(cos_i: f64, eta: &Rgb<f64>, k: &Rgb<f64>) -> Rgb<f64> {
+    Rgb::new(
+        fresnel_conductor(cos_i, eta.red(), k.red()),
+        fresnel_conductor(cos_i, eta.green(), k.green()),
+        fresnel_conductor(cos_i, eta.blue(), k.blue())
+    )
+}
+
+
+// GGX distribution of microfacet normals with Smith height-correlated masking and
+// shadowing. Directions are in the local frame, with z along the normal.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TrowbridgeReitz {
+    pub alpha_x: f64,
+    pub alpha_y: f64
+}
+
+impl TrowbridgeReitz {
+
+    // Perceptual roughness in [0, 1] along the tangent and the bitangent, squared
+    // into alpha
+    pub fn new(roughness_u: f64, roughness_v: f64) -> Self {
+        TrowbridgeReitz {
+            alpha_x: (roughness_u * roughness_u).max(1e-4),
+            alpha_y: (roughness_v * roughness_v).max(1e-4)
+        }
+    }
+
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    pub fn d(&self, wm: &Vector3<f64>) -> f64 {
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let e = x * x + y * y + wm.z * wm.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    fn lambda(&self, w: &Vector3<f64>) -> f64 {
+        if w.z == 0.0 {
+            return std::f64::INFINITY
+        }
+        let x = self.alpha_x * w.x;
+        let y = self.alpha_y * w.y;
+        ((1.0 + (x * x + y * y) / (w.z * w.z)).sqrt() - 1.0) / 2.0
+    }
+
+    pub fn g1(&self, w: &Vector3<f64>) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    pub fn g(&self, wo: &Vector3<f64>, wi: &Vector3<f64>) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the normals visible from `w`
+    pub fn d_visible(&self, w: &Vector3<f64>, wm: &Vector3<f64>) -> f64 {
+        if w.z == 0.0 {
+            return 0.0
+        }
+        self.g1(w) / w.z.abs() * self.d(wm) * w.dot(*wm).abs()
+    }
+
+    // Visible normal from `w`, by Heitz's sampling of the stretched hemisphere
+    pub fn sample_visible(&self, w: &Vector3<f64>) -> Vector3<f64> {
+        let mut rng = rand::thread_rng();
+        let mut wh = vec3(self.alpha_x * w.x, self.alpha_y * w.y, w.z).normalize();
+        if wh.z < 0.0 {
+            wh = -wh;
+        }
+        let t1 = if wh.z < 0.99999 { vec3(0.0, 0.0, 1.0).cross(wh).normalize() } else { vec3(1.0, 0.0, 0.0) };
+        let t2 = wh.cross(t1);
+
+        let r = rng.gen::<f64>().sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        let px = r * phi.cos();
+        let py = r * phi.sin();
+        let h = (1.0 - px * px).sqrt();
+        let s = (1.0 + wh.z) / 2.0;
+        let py = (1.0 - s) * h + s * py;
+        let pz = (1.0 - px * px - py * py).max(0.0).sqrt();
+
+        let nh = t1 * px + t2 * py + wh * pz;
+        vec3(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalize()
+    }
+
+}
+
+
+// Visible normal sampling around the outgoing direction `wo`. Conductors reflect off
+// the sampled normal, dielectrics with `eta` reflect or refract according to Fresnel.
+pub struct MicrofacetPdf {
+    frame: Onb,
+    wo: Vector3<f64>,
+    distribution: TrowbridgeReitz,
+    eta: Option<f64>
+}
+
+impl MicrofacetPdf {
+
+    pub fn new(frame: Onb, wo: Vector3<f64>, distribution: TrowbridgeReitz, eta: Option<f64>) -> Self {
+        MicrofacetPdf { frame, wo, distribution, eta }
+    }
+
+}
+
+impl Pdf for MicrofacetPdf {
+
+    fn value(&self, direction: &Vector3<f64>) -> f64 {
+        let wi = to_local(&self.frame, &direction.normalize());
+        let wo = self.wo;
+        match self.eta {
+            None => {
+                if wi.z <= 0.0 {
+                    return 0.0
+                }
+                let wm = (wo + wi).normalize();
+                self.distribution.d_visible(&wo, &wm) / (4.0 * wo.dot(wm).abs())
+            },
+            Some(eta) => {
+                let wm = match half_vector(&wo, &wi, eta) {
+                    Some(wm) => wm,
+                    None => return 0.0
+                };
+                let r = fresnel_dielectric(wo.dot(wm), eta);
+                let d_visible = self.distribution.d_visible(&wo, &wm);
+                if wi.z > 0.0 {
+                    d_visible / (4.0 * wo.dot(wm).abs()) * r
+                } else {
+                    let denom = wi.dot(wm) + wo.dot(wm) / eta;
+                    d_visible * wi.dot(wm).abs() / (denom * denom) * (1.0 - r)
+                }
+            }
+        }
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        let wo = self.wo;
+        let wm = self.distribution.sample_visible(&wo);
+        let wi = match self.eta {
+            None => reflect(&wo, &wm),
+            Some(eta) => {
+                let r = fresnel_dielectric(wo.dot(wm), eta);
+                if rand::thread_rng().gen::<f64>() < r {
+                    reflect(&wo, &wm)
+                } else {
+                    refract(&wo, &wm, eta).unwrap_or_else(|| reflect(&wo, &wm))
+                }
+            }
+        };
+        self.frame.local(wi.x, wi.y, wi.z)
+    }
+
+}
+
+
+// Microfacet normal between `wo` and `wi` facing the side of `wo`, generalized to
+// refraction. `None` when the pair cannot be connected through a front facing normal.
+fn half_vector(wo: &Vector3<f64>, wi: &Vector3<f64>, eta: f64) -> Option<Vector3<f64>> {
+    if wo.z == 0.0 || wi.z == 0.0 {
+        return None
+    }
+    let etap = if wi.z > 0.0 { 1.0 } else { eta };
+    let wm = wi * etap + wo;
+    if vec_near_zero(wm) {
+        return None
+    }
+    let mut wm = wm.normalize();
+    if wm.z < 0.0 {
+        wm = -wm;
+    }
+    if wm.dot(*wi) * wi.z < 0.0 || wm.dot(*wo) * wo.z < 0.0 {
+        return None
+    }
+    Some(wm)
+}
+
+
+// Rough dielectric BSDF times the cosine of `wi`, in the local frame
+pub fn rough_dielectric(distribution: &TrowbridgeReitz, wo: &Vector3<f64>, wi: &Vector3<f64>,
+                        eta: f64) -> f64 {
+    let wm = match half_vector(wo, wi, eta) {
+        Some(wm) => wm,
+        None => return 0.0
+    };
+
+    let d = distribution.d(&wm);
+    let g = distribution.g(wo, wi);
+    let fr = fresnel_dielectric(wo.dot(wm), eta);
+    if wi.z > 0.0 {
+        d * g * fr / (4.0 * wo.z.abs())
+    } else {
+        let denom = wi.dot(wm) + wo.dot(wm) / eta;
+        (1.0 - fr) * d * g * (wi.dot(wm) * wo.dot(wm) / (denom * denom * wo.z)).abs()
+    }
+}
+
+
+// Metal with a complex index of refraction per channel. Spectral paths uplift it as
+// raw coefficients, not like a color.
+pub struct RoughConductor {
+    distribution: TrowbridgeReitz,
+    eta: Rgb<f64>,
+    k: Rgb<f64>
+}
+
+impl RoughConductor {
+
+    pub fn new(eta: Rgb<f64>, k: Rgb<f64>, roughness_u: f64, roughness_v: f64) -> Self {
+        RoughConductor { distribution: TrowbridgeReitz::new(roughness_u, roughness_v), eta, k }
+    }
+
+    pub fn gold(roughness: f64) -> Self {
+        RoughConductor::new(Rgb::new(0.143, 0.374, 1.442), Rgb::new(3.983, 2.385, 1.603),
+                            roughness, roughness)
+    }
+
+    pub fn copper(roughness_u: f64, roughness_v: f64) -> Self {
+        RoughConductor::new(Rgb::new(0.200, 0.924, 1.102), Rgb::new(3.912, 2.452, 2.142),
+                            roughness_u, roughness_v)
+    }
+
+    fn fresnel(&self, cos_i: f64, r: &Ray) -> Rgb<f64> {
+        fresnel_conductor_rgb(cos_i, &sample_coefficients(&self.eta, &r.wavelengths), &sample_coefficients(&self.k, &r.wavelengths))
+    }
+
+}
+
+impl Material for RoughConductor {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        if wo.z <= 0.0 {
+            return false
+        }
+
+        if self.distribution.is_smooth() {
+            srec.attenuation = self.fresnel(wo.z, r);
+            srec.is_specular = true;
+            srec.specular_ray = Ray {
+                origin: rec.p,
+                dir: frame.local(-wo.x, -wo.y, wo.z),
+                wavelengths: r.wavelengths,
+                channel: r.channel
+            };
+            srec.pdf = None;
+            return true
+        }
+
+        srec.attenuation = self.fresnel(wo.z, r);
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(MicrofacetPdf::new(frame, wo, self.distribution, None)));
+        true
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let black = Rgb::new(0.0, 0.0, 0.0);
+        if self.distribution.is_smooth() {
+            return black
+        }
+
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        let wi = to_local(&frame, &scattered.dir.normalize());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return black
+        }
+
+        let wm = (wo + wi).normalize();
+        let f = self.fresnel(wo.dot(wm).abs(), r);
+        // Includes the cosine of `wi`, which cancels against the denominator
+        let k = self.distribution.d(&wm) * self.distribution.g(&wo, &wi) / (4.0 * wo.z);
+        Rgb::new(f.red() * k, f.green() * k, f.blue() * k)
+    }
+
+}
+
+
+// Glass with a rough surface, reflecting and refracting through the microfacets.
+// Like `Dielectric`, transmitted radiance is not scaled by the squared index ratio,
+// which cancels out for light entering and leaving closed objects.
+pub struct RoughDielectric {
+    distribution: TrowbridgeReitz,
+    ior: f64
+}
+
+impl RoughDielectric {
+
+    pub fn new(ior: f64, roughness_u: f64, roughness_v: f64) -> Self {
+        RoughDielectric { distribution: TrowbridgeReitz::new(roughness_u, roughness_v), ior }
+    }
+
+}
+
+impl Material for RoughDielectric {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        let eta = if rec.front_face { self.ior } else { 1.0 / self.ior };
+        srec.attenuation = Rgb::new(1.0, 1.0, 1.0);
+
+        if self.distribution.is_smooth() {
+            let n = vec3(0.0, 0.0, 1.0);
+            let wi = if rand::thread_rng().gen::<f64>() < fresnel_dielectric(wo.z, eta) {
+                reflect(&wo, &n)
+            } else {
+                refract(&wo, &n, eta).unwrap_or_else(|| reflect(&wo, &n))
+            };
+            srec.is_specular = true;
+            srec.specular_ray = Ray {
+                origin: rec.p,
+                dir: frame.local(wi.x, wi.y, wi.z),
+                wavelengths: r.wavelengths,
+                channel: r.channel
+            };
+            srec.pdf = None;
+            return true
+        }
+
+        srec.is_specular = false;
+        srec.pdf = Some(Box::new(MicrofacetPdf::new(frame, wo, self.distribution, Some(eta))));
+        true
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let black = Rgb::new(0.0, 0.0, 0.0);
+        if self.distribution.is_smooth() {
+            return black
+        }
+
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        let wi = to_local(&frame, &scattered.dir.normalize());
+        let eta = if rec.front_face { self.ior } else { 1.0 / self.ior };
+        let f = rough_dielectric(&self.distribution, &wo, &wi, eta);
+        Rgb::new(f, f, f)
+    }
+
+}
@@ -0,0 +1,170 @@
+use cgmath::{Vector3, InnerSpace};
+use num::clamp;
+use rand::Rng;
+use std::f64::consts::PI;
+
+use crate::util::{Onb, random_cosine_direction, random_unit_vec};
+
+
+pub trait Pdf {
+    fn value(&self, direction: &Vector3<f64>) -> f64;
+    fn generate(&self) -> Vector3<f64>;
+}
+
+
+pub struct CosinePdf {
+    uvw: Onb
+}
+
+impl CosinePdf {
+
+    pub fn new(w: Vector3<f64>) -> Self {
+        CosinePdf { uvw: Onb::from_w(w) }
+    }
+
+}
+
+impl Pdf for CosinePdf {
+
+    fn value(&self, direction: &Vector3<f64>) -> f64 {
+        let cosine = direction.normalize().dot(self.uvw.w);
+        if cosine <= 0.0 { 0.0 } else { cosine / PI }
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        let d = random_cosine_direction();
+        self.uvw.local(d.x, d.y, d.z)
+    }
+
+}
+
+
+pub struct SpherePdf;
+
+impl Pdf for SpherePdf {
+
+    fn value(&self, _direction: &Vector3<f64>) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        random_unit_vec()
+    }
+
+}
+
+
+// Normalized cos^n lobe around `w`
+pub struct PhongPdf {
+    uvw: Onb,
+    exponent: f64
+}
+
+impl PhongPdf {
+
+    pub fn new(w: Vector3<f64>, exponent: f64) -> Self {
+        PhongPdf { uvw: Onb::from_w(w), exponent }
+    }
+
+}
+
+impl Pdf for PhongPdf {
+
+    fn value(&self, direction: &Vector3<f64>) -> f64 {
+        let cosine = direction.normalize().dot(self.uvw.w);
+        if cosine <= 0.0 {
+            0.0
+        } else {
+            (self.exponent + 1.0) / (2.0 * PI) * cosine.powf(self.exponent)
+        }
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        let mut rng = rand::thread_rng();
+        let cos_theta = rng.gen::<f64>().powf(1.0 / (self.exponent + 1.0));
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        self.uvw.local(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+}
+
+
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+
+// Henyey-Greenstein phase function around the direction of propagation
+pub struct HenyeyGreensteinPdf {
+    uvw: Onb,
+    g: f64
+}
+
+impl HenyeyGreensteinPdf {
+
+    pub fn new(w: Vector3<f64>, g: f64) -> Self {
+        HenyeyGreensteinPdf { uvw: Onb::from_w(w), g }
+    }
+
+}
+
+impl Pdf for HenyeyGreensteinPdf {
+
+    fn value(&self, direction: &Vector3<f64>) -> f64 {
+        henyey_greenstein(direction.normalize().dot(self.uvw.w), self.g)
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        let mut rng = rand::thread_rng();
+        let g = self.g;
+        let xi: f64 = rng.gen();
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * xi
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+            clamp((1.0 + g * g - s * s) / (2.0 * g), -1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+
+        self.uvw.local(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+}
+
+
+// Samples one of several pdfs, picked by their weights. Weights summing to less than
+// one give the density of a material that samples specular lobes the rest of the time.
+pub struct MixturePdf {
+    pdfs: Vec<(f64, Box<dyn Pdf>)>
+}
+
+impl MixturePdf {
+
+    pub fn new(pdfs: Vec<(f64, Box<dyn Pdf>)>) -> Self {
+        MixturePdf { pdfs }
+    }
+
+}
+
+impl Pdf for MixturePdf {
+
+    fn value(&self, direction: &Vector3<f64>) -> f64 {
+        self.pdfs.iter().map(|(weight, pdf)| weight * pdf.value(direction)).sum()
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        let mut xi = rand::thread_rng().gen::<f64>();
+        for (weight, pdf) in self.pdfs.iter() {
+            if xi < *weight {
+                return pdf.generate()
+            }
+            xi -= weight;
+        }
+        self.pdfs.last().unwrap().1.generate()
+    }
+
+}
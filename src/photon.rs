@@ -0,0 +1,325 @@
+use cgmath::{Point3, Vector3, InnerSpace};
+use prisma::Rgb;
+use rand::Rng;
+use rayon::prelude::*;
+use std::f64::consts::PI;
+
+use crate::aov::SampleAovs;
+use crate::hittable::{Hittable, HitRecord};
+use crate::kdtree::KdTree;
+use crate::material::ScatterRecord;
+use crate::raytracing::{Ray, SamplingStrategy, background, sample_light};
+use crate::scene::World;
+use crate::util::{Onb, add_colors, mul_colors, random_cosine_direction, random_unit_vec, random_vec_in_unit_disk, scale_color};
+
+
+// Neighbours and largest radius of the radiance estimates. Caustics are sharp and
+// densely sampled, the global map only has to be smooth enough for final gathering.
+const CAUSTIC_NEIGHBOURS: usize = 50;
+const CAUSTIC_RADIUS: f64 = 0.25;
+const GLOBAL_NEIGHBOURS: usize = 100;
+const GLOBAL_RADIUS: f64 = 1.0;
+
+
+pub struct Photon {
+    // Normalized direction of travel
+    pub dir: Vector3<f64>,
+    pub power: Rgb<f64>
+}
+
+
+pub struct PhotonMapper {
+    pub max_depth: usize,
+    pub rr_min_depth: usize,
+    // Photons that reached a diffuse surface through specular bounces only
+    caustic: KdTree<Photon>,
+    // Photons at every diffuse surface they reached, direct ones included
+    global: KdTree<Photon>,
+    emitted: usize
+}
+
+impl PhotonMapper {
+
+    // Traces `photons` photons from the lights and, when the scene has an extent, from
+    // the background
+    pub fn build(world: &World, photons: usize, max_depth: usize, rr_min_depth: usize) -> Self {
+        if background_emits(world) && world.extent.is_none() {
+            eprintln!("Warning: the scene has no extent, its background lights nothing in the photon maps");
+        }
+        let (caustic, global) = (0..photons)
+            .into_par_iter()
+            .fold(|| (Vec::new(), Vec::new()), |(mut caustic, mut global), _| {
+                trace_photon(world, max_depth, rr_min_depth, &mut caustic, &mut global);
+                (caustic, global)
+            })
+            .reduce(|| (Vec::new(), Vec::new()), |(mut c0, mut g0), (c1, g1)| {
+                c0.extend(c1);
+                g0.extend(g1);
+                (c0, g0)
+            });
+
+        PhotonMapper {
+            max_depth,
+            rr_min_depth,
+            caustic: KdTree::new(caustic),
+            global: KdTree::new(global),
+            emitted: photons
+        }
+    }
+
+    // Camera paths follow specular bounces to the first diffuse hit, which adds direct
+    // lighting, the caustic map and one final gathering bounce into the global map.
+    // Light reached by other routes than those is left out as the maps already hold it.
+    pub fn ray_color<'w>(&self, r: &Ray, world: &'w World, aovs: &mut SampleAovs<'w>) -> Rgb<f64> {
+        let mut radiance = Rgb::new(0.0, 0.0, 0.0);
+        let mut throughput = Rgb::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        let mut rec = HitRecord::new();
+        let mut srec = ScatterRecord::new();
+
+        let mut gathering = false;
+        let mut direct_sampled = false;
+        let mut after_specular = false;
+        let mut first_specular = false;
+
+        for depth in 0..self.max_depth {
+            if !world.hit(&ray, 0.001, std::f64::INFINITY, &mut rec) {
+                // Background seen from a gather point through specular bounces is a caustic
+                if !(gathering && after_specular) {
+                    let escaped = mul_colors(&throughput, &background(&ray, world));
+                    if depth > 0 {
+                        aovs.lobes.add(first_specular, depth == 1, &escaped);
+                    }
+                    radiance = add_colors(&radiance, &escaped);
+                }
+                break
+            }
+
+            let material = rec.material.unwrap();
+            if depth == 0 {
+                aovs.first_hit = Some((rec, Rgb::new(0.0, 0.0, 0.0)));
+            }
+            if !direct_sampled || !world.is_light(rec.object_id) {
+                let emitted = mul_colors(&throughput, &material.emitted(&ray, &rec));
+                if depth > 0 {
+                    aovs.lobes.add(first_specular, depth == 1, &emitted);
+                }
+                radiance = add_colors(&radiance, &emitted);
+            }
+
+            if !material.scatter(&ray, &rec, &mut srec) {
+                break
+            }
+            if depth == 0 {
+                first_specular = srec.is_specular;
+            }
+
+            let pdf = srec.pdf.take();
+            if srec.is_specular {
+                // Lobes that are not delta ones are still lit directly, leaving the
+                // delta one to find emitters by itself
+                if let (Some(pdf), false) = (pdf.as_ref(), gathering) {
+                    let direct = mul_colors(&throughput,
+                        &sample_light(&ray, &rec, material, pdf.as_ref(), world, SamplingStrategy::Light));
+                    aovs.lobes.add(first_specular && depth > 0, depth == 0, &direct);
+                    radiance = add_colors(&radiance, &direct);
+                }
+
+                if let (0, Some(first_hit)) = (depth, aovs.first_hit.as_mut()) {
+                    first_hit.1 = srec.attenuation;
+                }
+                throughput = mul_colors(&throughput, &srec.attenuation);
+                ray = srec.specular_ray;
+                after_specular = true;
+            } else {
+                let pdf = pdf.unwrap();
+                if !material.is_volumetric() && gathering {
+                    let indirect = mul_colors(&throughput,
+                        &self.estimate(&self.global, GLOBAL_NEIGHBOURS, GLOBAL_RADIUS, &ray, &rec));
+                    aovs.lobes.add(first_specular, false, &indirect);
+                    radiance = add_colors(&radiance, &indirect);
+                    break
+                }
+
+                let direct = mul_colors(&throughput,
+                    &sample_light(&ray, &rec, material, pdf.as_ref(), world, SamplingStrategy::Light));
+                aovs.lobes.add(first_specular, depth == 0, &direct);
+                radiance = add_colors(&radiance, &direct);
+                if !material.is_volumetric() {
+                    let caustics = mul_colors(&throughput,
+                        &self.estimate(&self.caustic, CAUSTIC_NEIGHBOURS, CAUSTIC_RADIUS, &ray, &rec));
+                    aovs.lobes.add(first_specular, false, &caustics);
+                    radiance = add_colors(&radiance, &caustics);
+                    gathering = true;
+                }
+                direct_sampled = true;
+                after_specular = false;
+
+                let scattered = Ray { origin: rec.p, dir: pdf.generate(), wavelengths: ray.wavelengths, channel: ray.channel };
+                let pdf_value = pdf.value(&scattered.dir);
+                if pdf_value <= 0.0 {
+                    break
+                }
+
+                let f = scale_color(&material.eval(&ray, &rec, &scattered), 1.0 / pdf_value);
+                if let (0, Some(first_hit)) = (depth, aovs.first_hit.as_mut()) {
+                    first_hit.1 = f;
+                }
+                throughput = mul_colors(&throughput, &f);
+                ray = scattered;
+            }
+
+            if depth + 1 >= self.rr_min_depth {
+                let survival = throughput.red().max(throughput.green()).max(throughput.blue()).min(0.95);
+                if survival <= 0.0 || rand::thread_rng().gen::<f64>() >= survival {
+                    break
+                }
+                throughput = scale_color(&throughput, 1.0 / survival);
+            }
+        }
+
+        radiance
+    }
+
+    // Radiance reflected towards the origin of `r` from the photons around `rec.p`
+    fn estimate(&self, map: &KdTree<Photon>, neighbours: usize, max_radius: f64,
+                r: &Ray, rec: &HitRecord) -> Rgb<f64> {
+        let black = Rgb::new(0.0, 0.0, 0.0);
+        if map.is_empty() {
+            return black
+        }
+
+        let photons = map.nearest(rec.p, neighbours, max_radius);
+        if photons.is_empty() {
+            return black
+        }
+
+        // With too few photons around the lookup stays a fixed radius one
+        let radius2 = if photons.len() < neighbours {
+            max_radius * max_radius
+        } else {
+            photons.iter().fold(0.0, |r2: f64, (d2, _)| r2.max(*d2))
+        };
+
+        let reflected = photons.iter().fold(black, |sum, (_, photon)| {
+            let scattered = Ray { origin: rec.p, dir: -photon.dir, wavelengths: r.wavelengths, channel: r.channel };
+            let cosine = rec.normal.dot(scattered.dir);
+            if cosine <= 0.0 {
+                return sum
+            }
+            // `eval` includes the cosine towards the photon, the flux already accounts for it
+            let f = scale_color(&rec.material.unwrap().eval(r, rec, &scattered), 1.0 / cosine);
+            add_colors(&sum, &mul_colors(&f, &photon.power))
+        });
+
+        scale_color(&reflected, 1.0 / (PI * radius2 * self.emitted as f64))
+    }
+
+}
+
+
+fn background_emits(world: &World) -> bool {
+    match world.background {
+        Some(c) => c.red() > 0.0 || c.green() > 0.0 || c.blue() > 0.0,
+        None => true
+    }
+}
+
+
+// Sphere enclosing the scene that photons from the background are aimed at, if any
+fn sky_extent(world: &World) -> Option<(Point3<f64>, f64)> {
+    if background_emits(world) { world.extent } else { None }
+}
+
+
+// Emits one photon from a uniformly chosen emitter and stores it at every diffuse
+// surface along its path. Powers are left to be divided by the number of photons.
+fn trace_photon(world: &World, max_depth: usize, rr_min_depth: usize,
+                caustic: &mut Vec<(Point3<f64>, Photon)>,
+                global: &mut Vec<(Point3<f64>, Photon)>) {
+    let sky = sky_extent(world);
+    let emitters = world.lights.len() + sky.map_or(0, |_| 1);
+    if emitters == 0 {
+        return
+    }
+
+    let mut rng = rand::thread_rng();
+    let choice = rng.gen_range(0..emitters);
+    let (mut ray, mut power) = if choice < world.lights.len() {
+        let light_id = world.lights[choice];
+        let light = &world.objects[light_id];
+        let area = light.area();
+        let mut rec = HitRecord::new();
+        if area <= 0.0 || !light.sample_surface(&mut rec) {
+            return
+        }
+
+        let d = random_cosine_direction();
+        let dir = Onb::from_w(rec.normal).local(d.x, d.y, d.z);
+        let ray = Ray { origin: rec.p, dir, wavelengths: None, channel: None };
+        let emitted = rec.material.unwrap().emitted(&Ray { origin: rec.p + dir, dir: -dir, wavelengths: None, channel: None }, &rec);
+
+        // Cosine over the direction density leaves a factor of pi
+        (ray, scale_color(&emitted, PI * area * emitters as f64))
+    } else {
+        let (center, radius) = sky.unwrap();
+        let w = random_unit_vec();
+        let offset = random_vec_in_unit_disk() * radius;
+        let origin = center + w * radius + Onb::from_w(w).local(offset.x, offset.y, 0.0);
+        let emitted = background(&Ray { origin, dir: w, wavelengths: None, channel: None }, world);
+
+        (Ray { origin, dir: -w, wavelengths: None, channel: None }, scale_color(&emitted, 4.0 * PI * PI * radius * radius * emitters as f64))
+    };
+
+    let mut throughput = Rgb::new(1.0, 1.0, 1.0);
+    let mut specular_only = true;
+    let mut rec = HitRecord::new();
+    let mut srec = ScatterRecord::new();
+
+    for depth in 0..max_depth {
+        if !world.hit(&ray, 0.001, std::f64::INFINITY, &mut rec) {
+            break
+        }
+
+        let material = rec.material.unwrap();
+        if !material.scatter(&ray, &rec, &mut srec) {
+            break
+        }
+
+        if srec.is_specular {
+            power = mul_colors(&power, &srec.attenuation);
+            throughput = mul_colors(&throughput, &srec.attenuation);
+            ray = srec.specular_ray;
+        } else {
+            if !material.is_volumetric() {
+                let dir = ray.dir.normalize();
+                if specular_only && depth > 0 {
+                    caustic.push((rec.p, Photon { dir, power }));
+                }
+                global.push((rec.p, Photon { dir, power }));
+            }
+            specular_only = false;
+
+            let pdf = srec.pdf.take().unwrap();
+            let scattered = Ray { origin: rec.p, dir: pdf.generate(), wavelengths: None, channel: None };
+            let pdf_value = pdf.value(&scattered.dir);
+            if pdf_value <= 0.0 {
+                break
+            }
+
+            let f = scale_color(&material.eval(&ray, &rec, &scattered), 1.0 / pdf_value);
+            power = mul_colors(&power, &f);
+            throughput = mul_colors(&throughput, &f);
+            ray = scattered;
+        }
+
+        if depth + 1 >= rr_min_depth {
+            let survival = throughput.red().max(throughput.green()).max(throughput.blue()).min(0.95);
+            if survival <= 0.0 || rng.gen::<f64>() >= survival {
+                break
+            }
+            power = scale_color(&power, 1.0 / survival);
+            throughput = scale_color(&throughput, 1.0 / survival);
+        }
+    }
+}
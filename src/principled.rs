@@ -0,0 +1,344 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use prisma::{Rgb, Lerp};
+use rand::Rng;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::color::{Color, ColorSpace};
+use crate::hittable::HitRecord;
+use crate::material::{Material, ScatterRecord};
+use crate::microfacet::{MicrofacetPdf, TrowbridgeReitz, fresnel_dielectric, reflect, rough_dielectric,
+                        shading_frame, to_local};
+use crate::pdf::{CosinePdf, MixturePdf, Pdf};
+use crate::raytracing::Ray;
+use crate::spectrum::sample_rgb;
+use crate::texture::{SolidColor, Texture};
+use crate::util::{Onb, lerp, luminance, scale_color};
+
+
+fn schlick_weight(cosine: f64) -> f64 {
+    (1.0 - cosine).max(0.0).min(1.0).powi(5)
+}
+
+
+// Generalized Trowbridge-Reitz with an exponent of one, the long tailed distribution
+// of the clearcoat
+fn gtr1(cos_h: f64, alpha: f64) -> f64 {
+    if alpha >= 1.0 {
+        return 1.0 / PI
+    }
+    let a2 = alpha * alpha;
+    let t = 1.0 + (a2 - 1.0) * cos_h * cos_h;
+    (a2 - 1.0) / (PI * a2.ln() * t)
+}
+
+
+fn smith_g1(w: &Vector3<f64>, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    let cos2 = w.z * w.z;
+    2.0 / (1.0 + (1.0 + a2 * (1.0 - cos2) / cos2).sqrt())
+}
+
+
+// Reflection off half vectors distributed by GTR1
+struct ClearcoatPdf {
+    frame: Onb,
+    wo: Vector3<f64>,
+    alpha: f64
+}
+
+impl Pdf for ClearcoatPdf {
+
+    fn value(&self, direction: &Vector3<f64>) -> f64 {
+        let wi = to_local(&self.frame, &direction.normalize());
+        if wi.z <= 0.0 {
+            return 0.0
+        }
+        let h = (self.wo + wi).normalize();
+        gtr1(h.z, self.alpha) * h.z / (4.0 * self.wo.dot(h).abs())
+    }
+
+    fn generate(&self) -> Vector3<f64> {
+        let mut rng = rand::thread_rng();
+        let a2 = self.alpha * self.alpha;
+        let cos_theta = ((1.0 - a2.powf(1.0 - rng.gen::<f64>())) / (1.0 - a2)).max(0.0).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        let h = vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let wi = reflect(&self.wo, &h);
+        self.frame.local(wi.x, wi.y, wi.z)
+    }
+
+}
+
+
+// Disney's principled BSDF: a diffuse base with retro-reflection, sheen and a
+// subsurface approximation, a GGX specular lobe, rough glass transmission and a
+// clearcoat. All parameters but the index of refraction are in [0, 1].
+pub struct Principled {
+    base_color: Arc<dyn Texture>,
+    metallic: f64,
+    roughness: f64,
+    anisotropic: f64,
+    specular: f64,
+    specular_tint: f64,
+    sheen: f64,
+    sheen_tint: f64,
+    clearcoat: f64,
+    clearcoat_gloss: f64,
+    transmission: f64,
+    ior: f64,
+    subsurface: f64
+}
+
+// Colors of the lobes at a hit, uplifted for spectral paths
+struct Colors {
+    base: Rgb<f64>,
+    specular: Rgb<f64>,
+    sheen: Rgb<f64>
+}
+
+impl Principled {
+
+    pub fn new<C: Into<Color>>(base_color: C, space: ColorSpace) -> Self {
+        Principled::with_texture(Arc::new(SolidColor::new(base_color, space)))
+    }
+
+    pub fn with_texture(base_color: Arc<dyn Texture>) -> Self {
+        Principled {
+            base_color,
+            metallic: 0.0,
+            roughness: 0.5,
+            anisotropic: 0.0,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+            subsurface: 0.0
+        }
+    }
+
+    pub fn with_metallic(mut self, metallic: f64) -> Self {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    // Stretches the specular lobe along the tangent
+    pub fn with_anisotropic(mut self, anisotropic: f64) -> Self {
+        self.anisotropic = anisotropic;
+        self
+    }
+
+    // Dielectric reflectance at normal incidence, 0.5 giving 4 %, tinted towards the
+    // base color by `tint`
+    pub fn with_specular(mut self, specular: f64, tint: f64) -> Self {
+        self.specular = specular;
+        self.specular_tint = tint;
+        self
+    }
+
+    // Grazing retro-reflection for cloth, tinted towards the base color by `tint`
+    pub fn with_sheen(mut self, sheen: f64, tint: f64) -> Self {
+        self.sheen = sheen;
+        self.sheen_tint = tint;
+        self
+    }
+
+    pub fn with_clearcoat(mut self, clearcoat: f64, gloss: f64) -> Self {
+        self.clearcoat = clearcoat;
+        self.clearcoat_gloss = gloss;
+        self
+    }
+
+    // Share of the dielectric part refracting into the object, tinted by the base color
+    pub fn with_transmission(mut self, transmission: f64, ior: f64) -> Self {
+        self.transmission = transmission;
+        self.ior = ior;
+        self
+    }
+
+    // Blends the diffuse lobe into Hanrahan-Krueger's flatter subsurface approximation
+    pub fn with_subsurface(mut self, subsurface: f64) -> Self {
+        self.subsurface = subsurface;
+        self
+    }
+
+    fn distribution(&self) -> TrowbridgeReitz {
+        let aspect = (1.0 - 0.9 * self.anisotropic).sqrt();
+        let alpha = self.roughness * self.roughness;
+        TrowbridgeReitz {
+            alpha_x: (alpha / aspect).max(1e-3),
+            alpha_y: (alpha * aspect).max(1e-3)
+        }
+    }
+
+    fn clearcoat_alpha(&self) -> f64 {
+        lerp(0.1, 0.001, self.clearcoat_gloss)
+    }
+
+    fn diffuse_weight(&self) -> f64 {
+        (1.0 - self.metallic) * (1.0 - self.transmission)
+    }
+
+    fn transmission_weight(&self) -> f64 {
+        (1.0 - self.metallic) * self.transmission
+    }
+
+    // Colors in the working space, used for lobe selection
+    fn rgb_colors(&self, rec: &HitRecord) -> Colors {
+        let base = self.base_color.value(rec.u, rec.v, &rec.p);
+        let white = Rgb::new(1.0, 1.0, 1.0);
+        let l = luminance(&base);
+        let tint = if l > 0.0 { scale_color(&base, 1.0 / l) } else { white };
+        let dielectric = scale_color(&white.lerp(&tint, self.specular_tint), 0.08 * self.specular);
+        Colors {
+            base,
+            specular: dielectric.lerp(&base, self.metallic),
+            sheen: scale_color(&white.lerp(&tint, self.sheen_tint), self.sheen)
+        }
+    }
+
+    fn colors(&self, r: &Ray, rec: &HitRecord) -> Colors {
+        let c = self.rgb_colors(rec);
+        Colors {
+            base: sample_rgb(&c.base, &r.wavelengths),
+            specular: sample_rgb(&c.specular, &r.wavelengths),
+            sheen: sample_rgb(&c.sheen, &r.wavelengths)
+        }
+    }
+
+    // Probabilities of sampling the diffuse, specular, glass and clearcoat lobes
+    fn lobe_weights(&self, rec: &HitRecord, wo: &Vector3<f64>) -> [f64; 4] {
+        let c = self.rgb_colors(rec);
+        let fw = schlick_weight(wo.z);
+        let white = Rgb::new(1.0, 1.0, 1.0);
+        let weights = [
+            self.diffuse_weight() * luminance(&c.base).max(0.0),
+            (1.0 - self.transmission_weight()) * luminance(&c.specular.lerp(&white, fw)).max(0.01),
+            self.transmission_weight(),
+            0.25 * self.clearcoat * lerp(0.04, 1.0, fw)
+        ];
+        let total: f64 = weights.iter().sum();
+        [weights[0] / total, weights[1] / total, weights[2] / total, weights[3] / total]
+    }
+
+    // Hits from inside transmissive ones, which only light transmitted through the
+    // surface reaches. Back faces of opaque ones are shaded like front faces, the frame
+    // already being flipped towards the ray.
+    fn is_inside(&self, rec: &HitRecord) -> bool {
+        !rec.front_face && self.transmission_weight() > 0.0
+    }
+
+    fn eta(&self, rec: &HitRecord) -> f64 {
+        if rec.front_face { self.ior } else { 1.0 / self.ior }
+    }
+
+}
+
+impl Material for Principled {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        if wo.z <= 0.0 {
+            return false
+        }
+
+        srec.attenuation = self.colors(r, rec).base;
+        srec.is_specular = false;
+        let distribution = self.distribution();
+
+        // Only light that was transmitted reaches the inside, which is plain glass
+        if self.is_inside(rec) {
+            srec.pdf = Some(Box::new(MicrofacetPdf::new(frame, wo, distribution, Some(self.eta(rec)))));
+            return true
+        }
+
+        let weights = self.lobe_weights(rec, &wo);
+        let lobes: Vec<Box<dyn Pdf>> = vec![
+            Box::new(CosinePdf::new(rec.normal)),
+            Box::new(MicrofacetPdf::new(frame, wo, distribution, None)),
+            Box::new(MicrofacetPdf::new(frame, wo, distribution, Some(self.eta(rec)))),
+            Box::new(ClearcoatPdf { frame, wo, alpha: self.clearcoat_alpha() })
+        ];
+        let pdfs = weights.iter().copied().zip(lobes.into_iter())
+            .filter(|(weight, _)| *weight > 0.0)
+            .collect();
+        srec.pdf = Some(Box::new(MixturePdf::new(pdfs)));
+        true
+    }
+
+    fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Rgb<f64> {
+        let black = Rgb::new(0.0, 0.0, 0.0);
+        let frame = shading_frame(rec);
+        let wo = to_local(&frame, &-r.dir.normalize());
+        let wi = to_local(&frame, &scattered.dir.normalize());
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return black
+        }
+
+        let distribution = self.distribution();
+        let eta = self.eta(rec);
+        if self.is_inside(rec) {
+            let f = rough_dielectric(&distribution, &wo, &wi, eta);
+            return Rgb::new(f, f, f)
+        }
+
+        let c = self.colors(r, rec);
+        if wi.z < 0.0 {
+            let f = self.transmission_weight() * rough_dielectric(&distribution, &wo, &wi, eta);
+            return scale_color(&c.base, f)
+        }
+
+        let h = (wo + wi).normalize();
+        let cos_d = wi.dot(h);
+        let fl = schlick_weight(wi.z);
+        let fv = schlick_weight(wo.z);
+        let fh = schlick_weight(cos_d);
+
+        // Diffuse and sheen
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let fss90 = self.roughness * cos_d * cos_d;
+        let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+        let ss = 1.25 * (fss * (1.0 / (wi.z + wo.z) - 0.5) + 0.5);
+        let diffuse = scale_color(&c.base, lerp(fd, ss, self.subsurface) / PI);
+        let sheen = scale_color(&c.sheen, fh);
+        let base = scale_color(&Rgb::new(
+            diffuse.red() + sheen.red(),
+            diffuse.green() + sheen.green(),
+            diffuse.blue() + sheen.blue()
+        ), self.diffuse_weight());
+
+        // Specular, with the glass part reflecting by its exact Fresnel term
+        let schlick = c.specular.lerp(&Rgb::new(1.0, 1.0, 1.0), fh);
+        let t = self.transmission_weight();
+        let glass = t * fresnel_dielectric(wo.dot(h), eta);
+        let k = distribution.d(&h) * distribution.g(&wo, &wi) / (4.0 * wo.z * wi.z);
+        let specular = Rgb::new(
+            ((1.0 - t) * schlick.red() + glass) * k,
+            ((1.0 - t) * schlick.green() + glass) * k,
+            ((1.0 - t) * schlick.blue() + glass) * k
+        );
+
+        let alpha = self.clearcoat_alpha();
+        let clearcoat = 0.25 * self.clearcoat * gtr1(h.z, alpha) * lerp(0.04, 1.0, fh)
+            * smith_g1(&wo, 0.25) * smith_g1(&wi, 0.25) / (4.0 * wo.z * wi.z);
+
+        Rgb::new(
+            (base.red() + specular.red() + clearcoat) * wi.z,
+            (base.green() + specular.green() + clearcoat) * wi.z,
+            (base.blue() + specular.blue() + clearcoat) * wi.z
+        )
+    }
+
+}
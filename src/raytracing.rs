@@ -1,13 +1,25 @@
 use cgmath::{Point3, Vector3, InnerSpace, point3, vec3};
 use prisma::{Rgb, Lerp};
+use rand::Rng;
 
+use crate::aov::SampleAovs;
+use crate::color::ColorSpace;
 use crate::hittable::{Hittable, HitRecord};
-use crate::scene::SceneObject;
+use crate::material::{Material, ScatterRecord};
+use crate::pdf::Pdf;
+use crate::scene::World;
+use crate::spectrum::{Wavelengths, sample_rgb};
+use crate::util::{add_colors, mul_colors, scale_color};
 
 
+#[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Point3<f64>,
-    pub dir: Vector3<f64>
+    pub dir: Vector3<f64>,
+    // Set on the paths of spectral renders, whose colors then hold a value per wavelength
+    pub wavelengths: Option<Wavelengths>,
+    // Color channel an RGB path follows alone after dispersive refraction
+    pub channel: Option<usize>
 }
 
 impl Ray {
@@ -16,6 +28,8 @@ impl Ray {
         Ray {
             origin: point3(0.0, 0.0, 0.0),
             dir: vec3(0.0, 0.0, 0.0),
+            wavelengths: None,
+            channel: None
         }
     }
 
@@ -26,30 +40,190 @@ impl Ray {
 }
 
 
-pub fn ray_color(r: &Ray, object: &dyn SceneObject, depth: usize) -> Rgb<f64> {
-    let mut rec = HitRecord::new();
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplingStrategy {
+    // Lights are only found by following sampled scattering directions
+    Bsdf,
+    // Next-event estimation only, emission reached by scattering is ignored
+    Light,
+    // Both, combined with the power heuristic
+    Mis
+}
 
-    if depth <= 0 {
-        return Rgb::new(0.0, 0.0, 0.0)
-    }
 
-    if object.hit(&r, 0.001, std::f64::INFINITY, &mut rec) {
-        let mut scattered = Ray::new();
-        let mut attenuation = Rgb::new(0.0, 0.0, 0.0);
-        let material = rec.material.clone();
-        if material.unwrap().scatter(r, &rec, &mut attenuation, &mut scattered) {
-            let color = ray_color(&mut scattered, object, depth - 1);
-            return Rgb::new(
-                attenuation.red() * color.red(),
-                attenuation.green() * color.green(),
-                attenuation.blue() * color.blue()
-            )
-        }
-        return Rgb::new(0.0, 0.0, 0.0)
+fn power_heuristic(pdf_a: f64, pdf_b: f64) -> f64 {
+    let a = pdf_a * pdf_a;
+    let b = pdf_b * pdf_b;
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+
+pub fn background(r: &Ray, world: &World) -> Rgb<f64> {
+    if let Some(color) = world.background {
+        return sample_rgb(&color, &r.wavelengths)
     }
 
     let t = (r.dir.normalize().y + 1.0) * 0.5;
-    Rgb::new(1.0, 1.0, 1.0).lerp(
+    let sky = ColorSpace::LinearSrgb.convert(&Rgb::new(1.0, 1.0, 1.0).lerp(
         &Rgb::new(0.5, 0.7, 1.0),
-        t)
-}
\ No newline at end of file
+        t), world.working_space);
+    sample_rgb(&sky, &r.wavelengths)
+}
+
+
+// Solid angle density of next-event estimation choosing the direction of `r`
+fn light_pdf(r: &Ray, world: &World, light_id: usize) -> f64 {
+    world.objects[light_id].pdf_value(&r.origin, &r.dir) / world.lights.len() as f64
+}
+
+
+pub struct PathTracer {
+    // Hard cap on the number of bounces
+    pub max_depth: usize,
+    // Bounces after which paths are terminated by Russian roulette
+    pub rr_min_depth: usize,
+    pub sampling: SamplingStrategy
+}
+
+impl PathTracer {
+
+    // Also splits the radiance into lobes by the first bounce of the path and records
+    // the first hit in `aovs`
+    pub fn ray_color<'w>(&self, r: &Ray, world: &'w World, aovs: &mut SampleAovs<'w>) -> Rgb<f64> {
+        let mut radiance = Rgb::new(0.0, 0.0, 0.0);
+        let mut throughput = Rgb::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        let mut rec = HitRecord::new();
+        let mut srec = ScatterRecord::new();
+
+        // Density the current ray was sampled with at a non-specular hit, `None` for
+        // camera and specular rays. Light emission found that way is weighted against
+        // next-event estimation, which covers it on its own in `Light` mode.
+        let mut bsdf_pdf: Option<f64> = None;
+        let mut first_specular = false;
+
+        for depth in 0..self.max_depth {
+            if !world.hit(&ray, 0.001, std::f64::INFINITY, &mut rec) {
+                let escaped = mul_colors(&throughput, &background(&ray, world));
+                if depth > 0 {
+                    aovs.lobes.add(first_specular, depth == 1, &escaped);
+                }
+                radiance = add_colors(&radiance, &escaped);
+                break
+            }
+
+            let material = rec.material.unwrap();
+            if depth == 0 {
+                aovs.first_hit = Some((rec, Rgb::new(0.0, 0.0, 0.0)));
+            }
+            let mut emitted = material.emitted(&ray, &rec);
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                if world.is_light(rec.object_id) {
+                    let weight = match self.sampling {
+                        SamplingStrategy::Bsdf => 1.0,
+                        SamplingStrategy::Light => 0.0,
+                        SamplingStrategy::Mis =>
+                            power_heuristic(bsdf_pdf, light_pdf(&ray, world, rec.object_id))
+                    };
+                    emitted = scale_color(&emitted, weight);
+                }
+            }
+            let emitted = mul_colors(&throughput, &emitted);
+            if depth > 0 {
+                aovs.lobes.add(first_specular, depth == 1, &emitted);
+            }
+            radiance = add_colors(&radiance, &emitted);
+
+            if !material.scatter(&ray, &rec, &mut srec) {
+                break
+            }
+            if depth == 0 {
+                first_specular = srec.is_specular;
+            }
+
+            // Lobes that are not delta ones are lit directly even when the sample follows
+            // a delta one, which their density is weighted for
+            let pdf = srec.pdf.take();
+            if let (Some(pdf), true) = (pdf.as_ref(), self.sampling != SamplingStrategy::Bsdf) {
+                let direct = mul_colors(&throughput,
+                    &sample_light(&ray, &rec, material, pdf.as_ref(), world, self.sampling));
+                aovs.lobes.add(first_specular && depth > 0, depth == 0, &direct);
+                radiance = add_colors(&radiance, &direct);
+            }
+
+            if srec.is_specular {
+                if let (0, Some(first_hit)) = (depth, aovs.first_hit.as_mut()) {
+                    first_hit.1 = srec.attenuation;
+                }
+                throughput = mul_colors(&throughput, &srec.attenuation);
+                ray = srec.specular_ray;
+                bsdf_pdf = None;
+            } else {
+                let pdf = pdf.unwrap();
+                let scattered = Ray { origin: rec.p, dir: pdf.generate(), wavelengths: ray.wavelengths, channel: ray.channel };
+                let pdf_value = pdf.value(&scattered.dir);
+                if pdf_value <= 0.0 {
+                    break
+                }
+
+                let f = scale_color(&material.eval(&ray, &rec, &scattered), 1.0 / pdf_value);
+                if let (0, Some(first_hit)) = (depth, aovs.first_hit.as_mut()) {
+                    first_hit.1 = f;
+                }
+                throughput = mul_colors(&throughput, &f);
+                ray = scattered;
+                bsdf_pdf = Some(pdf_value);
+            }
+
+            // Unbiased termination: survivors are reweighted by the survival probability
+            if depth + 1 >= self.rr_min_depth {
+                let survival = throughput.red().max(throughput.green()).max(throughput.blue()).min(0.95);
+                if survival <= 0.0 || rand::thread_rng().gen::<f64>() >= survival {
+                    break
+                }
+                throughput = scale_color(&throughput, 1.0 / survival);
+            }
+        }
+
+        radiance
+    }
+
+}
+
+
+// Direct lighting from one uniformly chosen light through a shadow ray
+pub fn sample_light(r: &Ray, rec: &HitRecord, material: &dyn Material, bsdf_pdf: &dyn Pdf,
+                world: &World, strategy: SamplingStrategy) -> Rgb<f64> {
+    let black = Rgb::new(0.0, 0.0, 0.0);
+    if world.lights.is_empty() {
+        return black
+    }
+
+    let light_id = world.lights[rand::thread_rng().gen_range(0..world.lights.len())];
+    let light = &world.objects[light_id];
+
+    let shadow_ray = Ray { origin: rec.p, dir: light.random(&rec.p), wavelengths: r.wavelengths, channel: r.channel };
+    let pdf_value = light_pdf(&shadow_ray, world, light_id);
+    if pdf_value <= 0.0 {
+        return black
+    }
+
+    let f = material.eval(r, rec, &shadow_ray);
+    if f.red() <= 0.0 && f.green() <= 0.0 && f.blue() <= 0.0 {
+        return black
+    }
+
+    let mut light_rec = HitRecord::new();
+    if !world.hit(&shadow_ray, 0.001, std::f64::INFINITY, &mut light_rec) ||
+        light_rec.object_id != light_id {
+        return black
+    }
+
+    let weight = match strategy {
+        SamplingStrategy::Mis => power_heuristic(pdf_value, bsdf_pdf.value(&shadow_ray.dir)),
+        _ => 1.0
+    };
+
+    let emitted = light_rec.material.unwrap().emitted(&shadow_ray, &light_rec);
+    scale_color(&mul_colors(&f, &emitted), weight / pdf_value)
+}
@@ -2,58 +2,169 @@ use prisma::Rgb;
 use itertools::Itertools;
 use rayon::prelude::*;
 use rand::{Rng, thread_rng};
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use crate::aov::{self, Aov, Lobes, PixelAovs, SampleAovs};
 use crate::hittable::Hittable;
-use crate::scene::{SceneObject, World};
+use crate::scene::World;
 use crate::camera::Camera;
-use crate::raytracing::ray_color;
-use crate::util::{add_colors, to_color};
+use crate::bdpt::Bidirectional;
+use crate::photon::PhotonMapper;
+use crate::raytracing::{PathTracer, Ray, SamplingStrategy};
+use crate::spectrum::Wavelengths;
+use crate::hdr::HdrImage;
+use crate::tonemap::{Operator, ToneMapper};
+use crate::util::add_colors;
 use crate::animation::Animated;
 
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Integrator {
+    PathTracing,
+    Bidirectional,
+    PhotonMapping
+}
+
+
 pub struct Config {
     image_width: usize,
     image_height: usize,
     samples_per_pixel: usize,
     time_samples: usize,
-    max_depth: usize
+    max_depth: usize,
+    rr_min_depth: usize,
+    sampling: SamplingStrategy,
+    integrator: Integrator,
+    photons: usize,
+    spectral: bool,
+    tone_mapper: ToneMapper
 }
 
 impl Config {
     pub fn new(image_width: usize, image_height: usize, samples_per_pixel: usize,
         time_samples: usize, max_depth: usize) -> Self {
-        Config {image_width, image_height, samples_per_pixel, time_samples, max_depth}
+        Config {
+            image_width,
+            image_height,
+            samples_per_pixel,
+            time_samples,
+            max_depth,
+            rr_min_depth: 5,
+            sampling: SamplingStrategy::Mis,
+            integrator: Integrator::PathTracing,
+            photons: 200_000,
+            spectral: false,
+            tone_mapper: ToneMapper::default()
+        }
+    }
+
+    pub fn with_russian_roulette(mut self, min_depth: usize) -> Self {
+        self.rr_min_depth = min_depth;
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: SamplingStrategy) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn with_integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    // Photons traced per time sample by the photon mapping integrator
+    pub fn with_photons(mut self, photons: usize) -> Self {
+        self.photons = photons;
+        self
+    }
+
+    // Traces every sample at its own wavelengths instead of in RGB. Photon mapping
+    // stays in RGB either way.
+    pub fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    // Exposure in stops and curve used when converting the render for display
+    pub fn with_tone_mapping(mut self, exposure: f64, operator: Operator) -> Self {
+        self.tone_mapper = ToneMapper::new(exposure, operator);
+        self
     }
+
+    pub fn tone_mapper(&self) -> &ToneMapper {
+        &self.tone_mapper
+    }
+}
+
+
+pub struct Framebuffer {
+    // Linear radiance averaged over the samples of each pixel
+    pub beauty: HdrImage,
+    pub aovs: Vec<Aov>
+}
+
+impl Framebuffer {
+
+    pub fn aov(&self, name: &str) -> Option<&Aov> {
+        self.aovs.iter().find(|aov| aov.name == name)
+    }
+
 }
 
 
 pub fn render(world: &mut World, camera: &Camera, time0: f64, time1: f64,
-              config: &Config) -> Vec<u32> {
+              config: &Config) -> Framebuffer {
 
     let black = Rgb::new(0.0, 0.0, 0.0);
     let mut image = vec!(black; config.image_width * config.image_height);
+    let mut aovs = vec!(PixelAovs::new(); config.image_width * config.image_height);
 
     let k = (time1 - time0) as f64 / config.time_samples as f64;
     for ts in 0..config.time_samples {
         let time = time0 + (ts as f64 * k);
         world.update(time);
 
-        let buffer = render_time_sample(world, camera, time, config);
+        let (buffer, aov_buffer) = match config.integrator {
+            Integrator::PathTracing | Integrator::PhotonMapping => render_time_sample(world, camera, time, config),
+            Integrator::Bidirectional => render_bidirectional(world, camera, time, config)
+        };
 
         image = image.iter().zip(buffer.iter())
             .map(|(&i, &b)| add_colors(&i, &b))
-            .collect()
+            .collect();
+        aovs = aovs.iter().zip(aov_buffer.iter())
+            .map(|(a, b)| a.merge(b))
+            .collect();
     }
 
     let n = config.samples_per_pixel * config.time_samples;
-    image.iter().map(|&i| to_color(&i, n)).collect()
+    let k = 1.0 / n as f64;
+    Framebuffer {
+        beauty: HdrImage::from_fn(config.image_width, config.image_height, 3, world.working_space, |i| {
+            vec![image[i].red() * k, image[i].green() * k, image[i].blue() * k]
+        }),
+        aovs: aov::collect(&aovs, config.image_width, config.image_height, n, world.working_space)
+    }
 }
 
 
-fn render_time_sample(world: &mut World, camera: &Camera, time: f64, config: &Config) -> Vec<Rgb<f64>> {
+fn render_time_sample(world: &mut World, camera: &Camera, time: f64, config: &Config)
+    -> (Vec<Rgb<f64>>, Vec<PixelAovs>) {
 
     let black = Rgb::new(0.0, 0.0, 0.0);
-    let mut buffer = vec!(black; config.image_width * config.image_height);
+    let mut buffer = Vec::with_capacity(config.image_width * config.image_height);
+
+    let integrator = PathTracer {
+        max_depth: config.max_depth,
+        rr_min_depth: config.rr_min_depth,
+        sampling: config.sampling
+    };
+    let photon_map = match config.integrator {
+        Integrator::PhotonMapping => Some(PhotonMapper::build(
+            world, config.photons, config.max_depth, config.rr_min_depth)),
+        _ => None
+    };
 
     (0..config.image_height).cartesian_product(0..config.image_width)
         .collect::<Vec<(usize, usize)>>()
@@ -70,13 +181,148 @@ fn render_time_sample(world: &mut World, camera: &Camera, time: f64, config: &Co
                     let s = (x + rng.gen::<f64>()) / (config.image_width - 1) as f64;
                     let t = 1.0 - (y + rng.gen::<f64>()) / (config.image_height - 1) as f64;
                     let r = camera.get_ray(s, t, time);
-                    ray_color(&r, world as &dyn SceneObject, config.max_depth)
+                    let mut sample = SampleAovs::new();
+                    let color = match &photon_map {
+                        Some(photon_map) => photon_map.ray_color(&r, world, &mut sample),
+                        None if config.spectral => {
+                            let w = Wavelengths::sample(world.working_space);
+                            let spectral = Ray { wavelengths: Some(w), ..r };
+                            let color = integrator.ray_color(&spectral, world, &mut sample);
+                            sample = sample.map(|c| w.to_rgb(c));
+                            w.to_rgb(&color)
+                        },
+                        None => integrator.ray_color(&r, world, &mut sample)
+                    };
+
+                    let mut aovs = PixelAovs::new();
+                    aovs.add_sample(&r, camera, &sample);
+                    (color, aovs)
                 })
-                .reduce(|| black,
-                        |a, b| add_colors(&a, &b)
+                .reduce(|| (black, PixelAovs::new()),
+                        |a, b| (add_colors(&a.0, &b.0), a.1.merge(&b.1))
                 )
         })
         .collect_into_vec(&mut buffer);
 
-    buffer
-}
\ No newline at end of file
+    buffer.into_iter().unzip()
+}
+
+
+// Film shared by all threads, accumulating with atomic adds on the bits of each channel
+struct AtomicFilm {
+    data: Vec<AtomicU64>
+}
+
+impl AtomicFilm {
+
+    fn new(pixels: usize) -> Self {
+        AtomicFilm { data: (0..pixels * 3).map(|_| AtomicU64::new(0f64.to_bits())).collect() }
+    }
+
+    fn add(&self, index: usize, c: &Rgb<f64>) {
+        for (channel, value) in [c.red(), c.green(), c.blue()].iter().enumerate() {
+            let cell = &self.data[index * 3 + channel];
+            let mut current = cell.load(Ordering::Relaxed);
+            loop {
+                let sum = (f64::from_bits(current) + value).to_bits();
+                match cell.compare_exchange_weak(current, sum, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual
+                }
+            }
+        }
+    }
+
+    fn into_colors(self) -> Vec<Rgb<f64>> {
+        self.data.chunks_exact(3)
+            .map(|c| Rgb::new(
+                f64::from_bits(c[0].load(Ordering::Relaxed)),
+                f64::from_bits(c[1].load(Ordering::Relaxed)),
+                f64::from_bits(c[2].load(Ordering::Relaxed))
+            ))
+            .collect()
+    }
+
+}
+
+
+// Light tracing strategies land anywhere on the film, so all pixels accumulate into
+// one film shared between the threads
+fn render_bidirectional(world: &mut World, camera: &Camera, time: f64, config: &Config)
+    -> (Vec<Rgb<f64>>, Vec<PixelAovs>) {
+
+    let width = config.image_width;
+    let height = config.image_height;
+
+    let integrator = Bidirectional {
+        camera,
+        max_depth: config.max_depth,
+        rr_min_depth: config.rr_min_depth,
+        film_scale: (width * height) as f64 / ((width - 1) * (height - 1)) as f64
+    };
+    let world = &*world;
+
+    let film = AtomicFilm::new(width * height);
+    let direct_splats = AtomicFilm::new(width * height);
+    let indirect_splats = AtomicFilm::new(width * height);
+    let mut aovs = Vec::with_capacity(width * height);
+    (0..width * height)
+        .into_par_iter()
+        .map(|index| {
+            let mut rng = rand::thread_rng();
+            let x = (index % width) as f64;
+            let y = (index / width) as f64;
+            let mut splats = Vec::new();
+            let mut aovs = PixelAovs::new();
+
+            for _ in 0..config.samples_per_pixel {
+                let s = (x + rng.gen::<f64>()) / (width - 1) as f64;
+                let t = 1.0 - (y + rng.gen::<f64>()) / (height - 1) as f64;
+                let r = camera.get_ray(s, t, time);
+                let mut sample = SampleAovs::new();
+                let c = if config.spectral {
+                    let w = Wavelengths::sample(world.working_space);
+                    let first = splats.len();
+                    let c = integrator.sample(&Ray { wavelengths: Some(w), ..r }, world, &mut splats, &mut sample);
+                    for splat in splats[first..].iter_mut() {
+                        splat.2 = w.to_rgb(&splat.2);
+                    }
+                    sample = sample.map(|c| w.to_rgb(c));
+                    w.to_rgb(&c)
+                } else {
+                    integrator.sample(&r, world, &mut splats, &mut sample)
+                };
+                film.add(index, &c);
+                aovs.add_sample(&r, camera, &sample);
+            }
+
+            // Light tracing paths always bounce diffusely first, at the light subpath's end
+            for (s, t, c, bounces) in splats {
+                let px = (s * (width - 1) as f64).floor();
+                let py = ((1.0 - t) * (height - 1) as f64).floor();
+                if px >= 0.0 && py >= 0.0 && (px as usize) < width && (py as usize) < height {
+                    let i = py as usize * width + px as usize;
+                    film.add(i, &c);
+                    match bounces {
+                        0 => {},
+                        1 => direct_splats.add(i, &c),
+                        _ => indirect_splats.add(i, &c)
+                    }
+                }
+            }
+
+            aovs
+        })
+        .collect_into_vec(&mut aovs);
+
+    let direct_splats = direct_splats.into_colors();
+    let indirect_splats = indirect_splats.into_colors();
+    for (i, pixel) in aovs.iter_mut().enumerate() {
+        let mut lobes = Lobes::new();
+        lobes.add(false, true, &direct_splats[i]);
+        lobes.add(false, false, &indirect_splats[i]);
+        pixel.add_lobes(&lobes);
+    }
+
+    (film.into_colors(), aovs)
+}
@@ -1,13 +1,24 @@
-use cgmath::{InnerSpace, point3, vec3};
+use cgmath::{InnerSpace, Point3, point3, vec3};
 use std::sync::Arc;
 use prisma::Rgb;
 
 use crate::hittable::{Hittable, HitRecord};
 use crate::raytracing::Ray;
 use crate::animation::Animated;
-use crate::geometry::{Sphere, AnimatedSphere};
-use crate::material::{Lambertian, Metal, Dielectric, Material};
+use crate::geometry::{Sphere, AnimatedSphere, Quad};
+use crate::material::{Lambertian, Metal, Dielectric, Dispersion, DiffuseLight, DiffuseTransmission, Hair, Material, Mix, OrenNayar};
 use crate::util::{random_color, random_color_range};
+use crate::coated::Coated;
+use crate::microfacet::{RoughConductor, RoughDielectric};
+use crate::principled::Principled;
+use crate::volume::{ConstantMedium, DensityGrid, HeterogeneousMedium, Subsurface};
+use crate::aabb::Aabb;
+use crate::heightfield::Heightfield;
+use crate::curve::{Curve, CurveSet, CurveType};
+use crate::util::random_unit_vec;
+use crate::texture::{CheckerTexture, ImageTexture, SolidColor};
+use crate::color::ColorSpace;
+use crate::sdf::{SdfShape, SdfSphere, SdfBox, RoundedBox, Torus, SmoothUnion, SmoothSubtraction, Repeat};
 use rand::{thread_rng, Rng};
 
 pub trait SceneObject : Animated + Hittable {}
@@ -15,20 +26,55 @@ impl<T: Animated + Hittable> SceneObject for T {}
 
 
 pub struct World {
-    pub objects: Vec<Box<dyn SceneObject>>
+    pub objects: Vec<Box<dyn SceneObject>>,
+    // Indices into `objects` of the emitters sampled for direct lighting
+    pub lights: Vec<usize>,
+    // Radiance of rays escaping the scene, the sky gradient when unset
+    pub background: Option<Rgb<f64>>,
+    // Space colors are rendered in, which materials convert their inputs into
+    pub working_space: ColorSpace,
+    // Center and radius of a sphere around the interesting part of the scene, which
+    // photons from the background are emitted towards. The photon mapper leaves any
+    // background but a black one out of its maps without it.
+    pub extent: Option<(Point3<f64>, f64)>
+}
+
+impl World {
+
+    pub fn new(working_space: ColorSpace) -> Self {
+        World {
+            objects: Vec::new(),
+            lights: Vec::new(),
+            background: None,
+            working_space,
+            extent: None
+        }
+    }
+
+    pub fn add_light(&mut self, light: Box<dyn SceneObject>) {
+        self.lights.push(self.objects.len());
+        self.objects.push(light);
+    }
+
+    pub fn is_light(&self, object_id: usize) -> bool {
+        self.lights.contains(&object_id)
+    }
+
 }
 
 impl Hittable for World {
 
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
         let mut hit_anything = false;
         let mut closest_so_far = t_max;
 
-        for object in &self.objects {
+        for (id, object) in self.objects.iter().enumerate() {
+            // Fresh per object, so fields a primitive leaves unset don't leak between them
             let mut temp_rec = HitRecord::new();
             if object.hit(r, t_min, closest_so_far, &mut temp_rec) {
                 hit_anything = true;
                 closest_so_far = temp_rec.t;
+                temp_rec.object_id = id;
                 *rec = temp_rec;
             }
         }
@@ -50,23 +96,24 @@ impl Animated for World {
 
 
 
-pub fn test_scene() -> World {
-    let mut world = World {objects: Vec::new()};
+pub fn test_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(0.0, 0.0, -1.0), 2.0));
 
     let material_ground = Arc::new(
-        Lambertian::new(Rgb::new(0.8, 0.8, 0.0))
+        Lambertian::new(Rgb::new(0.8, 0.8, 0.0), space)
     );
 
     let material_center = Arc::new(
-        Lambertian::new(Rgb::new(0.1, 0.2, 0.5))
+        Lambertian::new(Rgb::new(0.1, 0.2, 0.5), space)
     );
 
     let material_left = Arc::new(
-        Dielectric::new(1.5)
+        Dielectric::with_abbe(1.5168, 64.17)
     );
 
     let material_right = Arc::new(
-        Metal::new(Rgb::new(0.8, 0.6, 0.2), 0.0)
+        Metal::new(Rgb::new(0.8, 0.6, 0.2), 0.0, space)
     );
 
     world.objects.push(Box::new(Sphere {
@@ -114,14 +161,15 @@ impl InRange for f64 {
 }
 
 
-pub fn random_scene() -> World {
-    let mut world = World {objects: Vec::new()};
+pub fn random_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(0.0, 1.0, 0.0), 13.0));
 
     world.objects.push(Box::new(Sphere {
         center: point3(0.0, -1000.0, 0.0),
         radius: 1000.0,
         mat: Arc::new(
-            Lambertian::new(Rgb::new(0.5, 0.5, 0.5))
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
         )
     }));
 
@@ -141,11 +189,13 @@ pub fn random_scene() -> World {
                 let material = {
                     match thread_rng().gen::<f64>() {
                         x if x.in_range(0.0, 0.6) => Arc::new(Lambertian::new(
-                            random_color()
+                            random_color(),
+                            space
                         )) as Arc<dyn Material>,
                         x if x.in_range(0.6, 0.8) => Arc::new(Metal::new(
                             random_color_range(0.5..1.0),
-                            thread_rng().gen_range(0.0..0.5)
+                            thread_rng().gen_range(0.0..0.5),
+                            space
                         )) as Arc<dyn Material>,
                         _ => Arc::new(Dielectric::new(
                             1.5
@@ -174,14 +224,652 @@ pub fn random_scene() -> World {
     world.objects.push(Box::new(Sphere {
         center: point3(-4.0, 1.0, 0.0),
         radius: 1.0,
-        mat: Arc::new(Lambertian::new(Rgb::new(0.4, 0.2, 0.1)))
+        mat: Arc::new(Lambertian::new(Rgb::new(0.4, 0.2, 0.1), space))
     }));
 
     world.objects.push(Box::new(Sphere {
         center: point3(4.0, 1.0, 0.0),
         radius: 1.0,
-        mat: Arc::new(Metal::new(Rgb::new(0.7, 0.6, 0.5), 0.0))
+        mat: Arc::new(Metal::new(Rgb::new(0.7, 0.6, 0.5), 0.0, space))
+    }));
+
+    world
+}
+
+
+pub fn volume_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(0.5, 1.0, 0.5), 7.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    // Smoke
+    world.objects.push(Box::new(ConstantMedium::new(
+        Box::new(Sphere {
+            center: point3(-4.0, 1.0, 0.0),
+            radius: 1.0,
+            mat: Arc::new(Dielectric::new(1.5))
+        }),
+        1.0,
+        Rgb::new(0.2, 0.2, 0.2),
+        space
+    )));
+
+    // Subsurface-looking blob: a glass shell filled with a dense medium
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(Dielectric::new(1.5))
+    }));
+
+    world.objects.push(Box::new(ConstantMedium::new(
+        Box::new(Sphere {
+            center: point3(0.0, 1.0, 0.0),
+            radius: 0.99,
+            mat: Arc::new(Dielectric::new(1.5))
+        }),
+        5.0,
+        Rgb::new(0.2, 0.4, 0.9),
+        space
+    )));
+
+    // Fog
+    world.objects.push(Box::new(ConstantMedium::new(
+        Box::new(Sphere {
+            center: point3(4.0, 1.0, 0.0),
+            radius: 1.0,
+            mat: Arc::new(Dielectric::new(1.5))
+        }),
+        0.5,
+        Rgb::new(1.0, 1.0, 1.0),
+        space
+    )));
+
+    // Tinted glass, absorbing inside instead of scattering
+    world.objects.push(Box::new(Sphere {
+        center: point3(6.0, 0.7, 2.0),
+        radius: 0.7,
+        mat: Arc::new(Dielectric::new(1.5).with_transmittance(Rgb::new(0.9, 0.5, 0.2), 1.0, space))
+    }));
+
+    world
+}
+
+
+pub fn cloud_scene(space: ColorSpace, grid_path: Option<&str>) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(1.5, 2.0, 0.0), 5.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
     }));
 
+    // Procedural fallback: a few soft blobs falling off towards the edges of the grid
+    let puff = |x: f64, y: f64, z: f64| {
+        let blobs = [(0.5, 0.4, 0.5, 0.3), (0.3, 0.55, 0.45, 0.2), (0.7, 0.6, 0.55, 0.22)];
+        blobs.iter()
+            .map(|&(cx, cy, cz, r)| {
+                let d2 = (x - cx).powi(2) + (y - cy).powi(2) + (z - cz).powi(2);
+                (1.0 - d2 / (r * r)).max(0.0)
+            })
+            .sum::<f64>()
+    };
+
+    let grid = match grid_path {
+        Some(path) => DensityGrid::load(path).unwrap_or_else(|e| {
+            panic!("Failed to load density grid {}: {}", path, e);
+        }),
+        None => DensityGrid::from_fn(64, 64, 64, puff)
+    };
+
+    world.objects.push(Box::new(HeterogeneousMedium::new(
+        grid,
+        Aabb::new(point3(-2.0, 0.0, -2.0), point3(2.0, 4.0, 2.0)),
+        0.2,
+        4.0,
+        Rgb::new(1.0, 1.0, 1.0),
+        0.6,
+        space
+    )));
+
+    // Glowing core: mostly absorbing, emitting where absorption happens
+    world.objects.push(Box::new(HeterogeneousMedium::new(
+        DensityGrid::from_fn(32, 32, 32, |x, y, z| {
+            let d2 = (x - 0.5).powi(2) + (y - 0.5).powi(2) + (z - 0.5).powi(2);
+            (1.0 - 4.0 * d2).max(0.0)
+        }),
+        Aabb::new(point3(3.0, 0.2, -1.0), point3(5.0, 2.2, 1.0)),
+        3.0,
+        1.0,
+        Rgb::new(0.9, 0.6, 0.4),
+        -0.3,
+        space
+    ).with_emission(Rgb::new(2.0, 0.8, 0.2), space)));
+
     world
-}
\ No newline at end of file
+}
+
+
+pub fn sdf_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(-1.0, 1.0, 0.0), 8.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    // Two spheres melting into each other
+    world.objects.push(Box::new(SdfShape {
+        sdf: Box::new(SmoothUnion {
+            a: Box::new(SdfSphere { center: point3(-4.0, 0.8, -0.4), radius: 0.8 }),
+            b: Box::new(SdfSphere { center: point3(-4.0, 1.4, 0.6), radius: 0.6 }),
+            k: 0.5
+        }),
+        bounds: Aabb::new(point3(-5.0, 0.0, -1.5), point3(-3.0, 2.5, 1.5)),
+        mat: Arc::new(Lambertian::new(Rgb::new(0.8, 0.3, 0.2), space))
+    }));
+
+    // Rounded glass box with a ball carved out of it
+    world.objects.push(Box::new(SdfShape {
+        sdf: Box::new(SmoothSubtraction {
+            a: Box::new(RoundedBox {
+                center: point3(0.0, 1.0, 0.0),
+                half_extents: vec3(0.9, 0.9, 0.9),
+                radius: 0.2
+            }),
+            b: Box::new(SdfSphere { center: point3(0.0, 1.9, 0.0), radius: 0.6 }),
+            k: 0.1
+        }),
+        bounds: Aabb::new(point3(-1.0, 0.0, -1.0), point3(1.0, 2.0, 1.0)),
+        mat: Arc::new(Dielectric::new(1.5))
+    }));
+
+    world.objects.push(Box::new(SdfShape {
+        sdf: Box::new(Torus { center: point3(4.0, 0.3, 0.0), major_radius: 0.8, minor_radius: 0.3 }),
+        bounds: Aabb::new(point3(2.9, 0.0, -1.1), point3(5.1, 0.6, 1.1)),
+        mat: Arc::new(Metal::new(Rgb::new(0.8, 0.6, 0.2), 0.1, space))
+    }));
+
+    // A row of pillars from a single repeated box
+    world.objects.push(Box::new(SdfShape {
+        sdf: Box::new(Repeat {
+            sdf: Box::new(SdfBox { center: point3(-7.0, 0.75, 0.0), half_extents: vec3(0.15, 0.75, 0.15) }),
+            period: vec3(0.0, 0.0, 1.0)
+        }),
+        bounds: Aabb::new(point3(-7.2, -0.05, -4.2), point3(-6.8, 1.55, 4.2)),
+        mat: Arc::new(Lambertian::new(Rgb::new(0.7, 0.7, 0.7), space))
+    }));
+
+    world
+}
+
+
+pub fn terrain_scene(space: ColorSpace, heightmap_path: Option<&str>, colormap_path: Option<&str>) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(0.0, 1.0, 0.0), 15.0));
+
+    let mat: Arc<dyn Material> = match colormap_path {
+        Some(path) => Arc::new(Lambertian::with_texture(Arc::new(
+            ImageTexture::load(path, ColorSpace::Srgb, space).unwrap_or_else(|e| {
+                panic!("Failed to load color map {}: {}", path, e);
+            })
+        ))),
+        None => Arc::new(Lambertian::new(Rgb::new(0.4, 0.5, 0.3), space))
+    };
+
+    let origin = point3(-10.0, -1.0, -10.0);
+    let terrain = match heightmap_path {
+        Some(path) => Heightfield::from_image(path, origin, 20.0, 20.0, 3.0, mat)
+            .unwrap_or_else(|e| {
+                panic!("Failed to load heightmap {}: {}", path, e);
+            }),
+        None => {
+            // Procedural rolling hills
+            let n = 128;
+            let heights = (0..n * n)
+                .map(|k| {
+                    let x = (k % n) as f64 / (n - 1) as f64;
+                    let z = (k / n) as f64 / (n - 1) as f64;
+                    0.8 * (1.0 + (x * 9.0).sin() * (z * 7.0).cos())
+                        + 0.3 * (x * 23.0 + z * 17.0).sin()
+                })
+                .collect();
+            Heightfield::new(heights, n, n, origin, 20.0, 20.0, mat)
+        }
+    };
+    world.objects.push(Box::new(terrain));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 3.5, 0.0),
+        radius: 1.0,
+        mat: Arc::new(Metal::new(Rgb::new(0.7, 0.6, 0.5), 0.0, space))
+    }));
+
+    world
+}
+
+
+pub fn hair_scene(space: ColorSpace, strands_path: Option<&str>) -> World {
+    let mut world = World::new(space);
+    world.extent = Some((point3(0.0, 1.0, 0.0), 3.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    let hair: Arc<dyn Material> = Arc::new(Hair::from_melanin(0.3, 0.8, 0.3, 0.3, 2.0));
+
+    let strands = match strands_path {
+        Some(path) => CurveSet::load(path, CurveType::Tube, hair).unwrap_or_else(|e| {
+            panic!("Failed to load strands {}: {}", path, e);
+        }),
+        None => {
+            // Procedural fur ball: strands grown along the normal and pulled down by gravity
+            let center = point3(0.0, 1.0, 0.0);
+            let radius = 0.8;
+            let curves = (0..4000)
+                .map(|_| {
+                    let n = random_unit_vec();
+                    let root = center + n * radius;
+                    let length = thread_rng().gen_range(0.3..0.45);
+                    let droop = vec3(0.0, -0.25 * length, 0.0);
+                    Curve::new(
+                        [
+                            root,
+                            root + n * (length / 3.0),
+                            root + n * (2.0 * length / 3.0) + droop * 0.5,
+                            root + n * length + droop
+                        ],
+                        0.012,
+                        0.002,
+                        CurveType::Ribbon,
+                        hair.clone()
+                    )
+                })
+                .collect();
+
+            world.objects.push(Box::new(Sphere {
+                center,
+                radius,
+                mat: Arc::new(Lambertian::new(Rgb::new(0.3, 0.15, 0.05), space))
+            }));
+
+            CurveSet::new(curves)
+        }
+    };
+    world.objects.push(Box::new(strands));
+
+    world
+}
+
+
+pub fn lights_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(Rgb::new(0.0, 0.0, 0.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(-4.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(Lambertian::new(Rgb::new(0.4, 0.2, 0.1), space))
+    }));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(Dielectric::with_dispersion(Dispersion::SF11))
+    }));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(4.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(Metal::new(Rgb::new(0.7, 0.6, 0.5), 0.05, space))
+    }));
+
+    // Area light facing down
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    // Small, bright bulb
+    world.add_light(Box::new(Sphere {
+        center: point3(2.0, 0.4, 2.5),
+        radius: 0.2,
+        mat: Arc::new(DiffuseLight::new(Rgb::new(40.0, 30.0, 15.0), space))
+    }));
+
+    world
+}
+
+
+pub fn microfacet_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(ColorSpace::LinearSrgb.convert(&Rgb::new(0.05, 0.05, 0.08), space));
+    world.extent = Some((point3(0.0, 1.0, 0.0), 6.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(-4.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(RoughConductor::gold(0.3))
+    }));
+
+    // Brushed along the lines of latitude
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(RoughConductor::copper(0.6, 0.15))
+    }));
+
+    // Frosted glass
+    world.objects.push(Box::new(Sphere {
+        center: point3(4.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(RoughDielectric::new(1.5, 0.25, 0.25))
+    }));
+
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    world
+}
+
+
+pub fn principled_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(ColorSpace::LinearSrgb.convert(&Rgb::new(0.05, 0.05, 0.08), space));
+    world.extent = Some((point3(0.0, 1.0, 0.0), 5.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    let materials = vec![
+        // Lacquered plastic
+        Principled::new(Rgb::new(0.6, 0.05, 0.05), space)
+            .with_roughness(0.5)
+            .with_clearcoat(1.0, 0.9),
+        // Brushed steel
+        Principled::new(Rgb::new(0.6, 0.6, 0.62), space)
+            .with_metallic(1.0)
+            .with_roughness(0.4)
+            .with_anisotropic(0.8),
+        // Velvet
+        Principled::new(Rgb::new(0.1, 0.05, 0.3), space)
+            .with_roughness(1.0)
+            .with_sheen(1.0, 0.5)
+            .with_specular(0.2, 0.0),
+        // Wax
+        Principled::new(Rgb::new(0.8, 0.7, 0.5), space)
+            .with_roughness(0.3)
+            .with_subsurface(1.0)
+            .with_specular(0.5, 0.3),
+        // Frosted tinted glass
+        Principled::new(Rgb::new(0.7, 0.9, 0.8), space)
+            .with_roughness(0.15)
+            .with_transmission(1.0, 1.5)
+    ];
+
+    for (i, mat) in materials.into_iter().enumerate() {
+        world.objects.push(Box::new(Sphere {
+            center: point3(0.0, 0.6, -3.2 + 1.6 * i as f64),
+            radius: 0.6,
+            mat: Arc::new(mat)
+        }));
+    }
+
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    world
+}
+
+
+pub fn coated_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(ColorSpace::LinearSrgb.convert(&Rgb::new(0.05, 0.05, 0.08), space));
+    world.extent = Some((point3(0.0, 1.0, 0.0), 5.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    // Car paint
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, -2.4),
+        radius: 1.0,
+        mat: Arc::new(Coated::new(Arc::new(Lambertian::new(Rgb::new(0.05, 0.15, 0.5), space)), 1.5, 0.0))
+    }));
+
+    // Amber varnish over a light diffuse base
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(
+            Coated::new(Arc::new(Lambertian::new(Rgb::new(0.7, 0.6, 0.45), space)), 1.5, 0.2)
+                .with_tint(Rgb::new(0.9, 0.6, 0.3), space)
+        )
+    }));
+
+    // Lacquered brushed copper
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 2.4),
+        radius: 1.0,
+        mat: Arc::new(Coated::new(Arc::new(RoughConductor::copper(0.5, 0.1)), 1.5, 0.0))
+    }));
+
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    world
+}
+
+
+pub fn mix_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(ColorSpace::LinearSrgb.convert(&Rgb::new(0.05, 0.05, 0.08), space));
+    world.extent = Some((point3(0.0, 1.0, 0.0), 5.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    // Dirty gold
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, -2.4),
+        radius: 1.0,
+        mat: Arc::new(Mix::new(
+            Arc::new(RoughConductor::gold(0.2)),
+            Arc::new(Lambertian::new(Rgb::new(0.25, 0.2, 0.15), space)),
+            0.35
+        ))
+    }));
+
+    // Paint worn through to the steel underneath in patches
+    let mask = CheckerTexture::new(
+        0.3,
+        Arc::new(SolidColor::new(Rgb::new(0.0, 0.0, 0.0), space)),
+        Arc::new(SolidColor::new(Rgb::new(1.0, 1.0, 1.0), space))
+    );
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(Mix::with_mask(
+            Arc::new(Coated::new(Arc::new(Lambertian::new(Rgb::new(0.6, 0.08, 0.05), space)), 1.5, 0.0)),
+            Arc::new(RoughConductor::new(Rgb::new(2.9, 2.9, 2.6), Rgb::new(3.0, 2.9, 2.8), 0.4, 0.4)),
+            Arc::new(mask)
+        ))
+    }));
+
+    // Frosted glass with a dusting of chalk
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 2.4),
+        radius: 1.0,
+        mat: Arc::new(Mix::new(
+            Arc::new(Dielectric::new(1.5)),
+            Arc::new(Lambertian::new(Rgb::new(0.9, 0.9, 0.9), space)),
+            0.2
+        ))
+    }));
+
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    world
+}
+
+
+pub fn diffuse_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(ColorSpace::LinearSrgb.convert(&Rgb::new(0.05, 0.05, 0.08), space));
+    world.extent = Some((point3(0.0, 1.0, 0.0), 5.0));
+
+    // Concrete
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(OrenNayar::new(Rgb::new(0.5, 0.5, 0.48), 20.0, space))
+    }));
+
+    // Smooth and rough diffuse side by side
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, -2.4),
+        radius: 1.0,
+        mat: Arc::new(Lambertian::new(Rgb::new(0.7, 0.4, 0.3), space))
+    }));
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, 1.0, 0.0),
+        radius: 1.0,
+        mat: Arc::new(OrenNayar::new(Rgb::new(0.7, 0.4, 0.3), 40.0, space))
+    }));
+
+    // Paper screen lit from behind
+    world.objects.push(Box::new(Quad::new(
+        point3(0.0, 0.0, 1.6),
+        vec3(0.0, 0.0, 1.6),
+        vec3(0.0, 2.0, 0.0),
+        Arc::new(DiffuseTransmission::new(Rgb::new(0.5, 0.5, 0.45), Rgb::new(0.4, 0.35, 0.25), space))
+    )));
+    world.add_light(Box::new(Sphere {
+        center: point3(-1.5, 1.0, 2.4),
+        radius: 0.3,
+        mat: Arc::new(DiffuseLight::new(Rgb::new(8.0, 8.0, 8.0), space))
+    }));
+
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    world
+}
+
+
+pub fn subsurface_scene(space: ColorSpace) -> World {
+    let mut world = World::new(space);
+    world.background = Some(ColorSpace::LinearSrgb.convert(&Rgb::new(0.05, 0.05, 0.08), space));
+    world.extent = Some((point3(0.0, 1.0, 0.0), 5.0));
+
+    world.objects.push(Box::new(Sphere {
+        center: point3(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        mat: Arc::new(
+            Lambertian::new(Rgb::new(0.5, 0.5, 0.5), space)
+        )
+    }));
+
+    // Marble, skin and wax, from the shortest to the longest mean free paths
+    let materials = [
+        (Rgb::new(0.15, 0.15, 0.15), Rgb::new(0.99, 0.99, 0.98), 0.0),
+        (Rgb::new(0.4, 0.15, 0.08), Rgb::new(0.98, 0.9, 0.8), 0.0),
+        (Rgb::new(0.5, 0.35, 0.15), Rgb::new(0.99, 0.95, 0.8), 0.3)
+    ];
+    for (i, (mean_free_path, albedo, g)) in materials.iter().enumerate() {
+        world.objects.push(Box::new(Subsurface::new(
+            Box::new(Sphere {
+                center: point3(0.0, 1.0, -2.4 + 2.4 * i as f64),
+                radius: 1.0,
+                mat: Arc::new(Dielectric::new(1.4))
+            }),
+            1.4,
+            *mean_free_path,
+            *albedo,
+            *g,
+            space
+        )));
+    }
+
+    world.add_light(Box::new(Quad::new(
+        point3(-2.0, 5.0, -2.0),
+        vec3(4.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 4.0),
+        Arc::new(DiffuseLight::new(Rgb::new(4.0, 4.0, 4.0), space))
+    )));
+
+    world
+}
@@ -0,0 +1,226 @@
+use cgmath::{Point3, Vector3, InnerSpace, point3, vec3};
+use num::clamp;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::animation::Animated;
+use crate::raytracing::Ray;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Material;
+
+
+const MAX_STEPS: usize = 256;
+const HIT_EPSILON: f64 = 1e-4;
+const NORMAL_EPSILON: f64 = 1e-4;
+
+
+pub trait Sdf : Send + Sync {
+    fn distance(&self, p: Point3<f64>) -> f64;
+}
+
+
+pub struct SdfSphere {
+    pub center: Point3<f64>,
+    pub radius: f64
+}
+
+impl Sdf for SdfSphere {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        (p - self.center).magnitude() - self.radius
+    }
+
+}
+
+
+fn box_distance(q: Vector3<f64>, half_extents: Vector3<f64>) -> f64 {
+    let d = vec3(q.x.abs(), q.y.abs(), q.z.abs()) - half_extents;
+    let outside = vec3(d.x.max(0.0), d.y.max(0.0), d.z.max(0.0)).magnitude();
+    let inside = d.x.max(d.y).max(d.z).min(0.0);
+    outside + inside
+}
+
+
+pub struct SdfBox {
+    pub center: Point3<f64>,
+    pub half_extents: Vector3<f64>
+}
+
+impl Sdf for SdfBox {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        box_distance(p - self.center, self.half_extents)
+    }
+
+}
+
+
+pub struct RoundedBox {
+    pub center: Point3<f64>,
+    pub half_extents: Vector3<f64>,
+    pub radius: f64
+}
+
+impl Sdf for RoundedBox {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        let r = vec3(self.radius, self.radius, self.radius);
+        box_distance(p - self.center, self.half_extents - r) - self.radius
+    }
+
+}
+
+
+// Torus lying in the xz plane around its center
+pub struct Torus {
+    pub center: Point3<f64>,
+    pub major_radius: f64,
+    pub minor_radius: f64
+}
+
+impl Sdf for Torus {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        let q = p - self.center;
+        let ring = (q.x * q.x + q.z * q.z).sqrt() - self.major_radius;
+        (ring * ring + q.y * q.y).sqrt() - self.minor_radius
+    }
+
+}
+
+
+pub struct SmoothUnion {
+    pub a: Box<dyn Sdf>,
+    pub b: Box<dyn Sdf>,
+    pub k: f64
+}
+
+impl Sdf for SmoothUnion {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        let d1 = self.a.distance(p);
+        let d2 = self.b.distance(p);
+        if self.k <= 0.0 {
+            return d1.min(d2)
+        }
+        let h = clamp(0.5 + 0.5 * (d2 - d1) / self.k, 0.0, 1.0);
+        d2 + (d1 - d2) * h - self.k * h * (1.0 - h)
+    }
+
+}
+
+
+// Carves `b` out of `a`
+pub struct SmoothSubtraction {
+    pub a: Box<dyn Sdf>,
+    pub b: Box<dyn Sdf>,
+    pub k: f64
+}
+
+impl Sdf for SmoothSubtraction {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        let d1 = self.a.distance(p);
+        let d2 = self.b.distance(p);
+        if self.k <= 0.0 {
+            return d1.max(-d2)
+        }
+        let h = clamp(0.5 - 0.5 * (d1 + d2) / self.k, 0.0, 1.0);
+        d1 + (-d2 - d1) * h + self.k * h * (1.0 - h)
+    }
+
+}
+
+
+// Infinite domain repetition with the given cell size, a zero component disables
+// repetition along that axis. The repeated shape should be centered on the origin
+// along the axes it repeats on.
+pub struct Repeat {
+    pub sdf: Box<dyn Sdf>,
+    pub period: Vector3<f64>
+}
+
+impl Sdf for Repeat {
+
+    fn distance(&self, p: Point3<f64>) -> f64 {
+        let wrap = |x: f64, c: f64| if c > 0.0 { x - c * (x / c).round() } else { x };
+        self.sdf.distance(point3(
+            wrap(p.x, self.period.x),
+            wrap(p.y, self.period.y),
+            wrap(p.z, self.period.z)
+        ))
+    }
+
+}
+
+
+pub struct SdfShape {
+    pub sdf: Box<dyn Sdf>,
+    pub bounds: Aabb,
+    pub mat: Arc<dyn Material>
+}
+
+impl SdfShape {
+
+    fn normal(&self, p: Point3<f64>) -> Vector3<f64> {
+        let dx = vec3(NORMAL_EPSILON, 0.0, 0.0);
+        let dy = vec3(0.0, NORMAL_EPSILON, 0.0);
+        let dz = vec3(0.0, 0.0, NORMAL_EPSILON);
+        vec3(
+            self.sdf.distance(p + dx) - self.sdf.distance(p - dx),
+            self.sdf.distance(p + dy) - self.sdf.distance(p - dy),
+            self.sdf.distance(p + dz) - self.sdf.distance(p - dz)
+        ).normalize()
+    }
+
+}
+
+impl Hittable for SdfShape {
+
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        let (t_enter, t_exit) = match self.bounds.intersect(r, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false
+        };
+
+        let ray_length = r.dir.magnitude();
+        let mut t = t_enter;
+
+        // Rays starting inside the shape (e.g. refracted ones) march on the negated field.
+        // On the surface itself the side is decided by where the ray is heading.
+        let d0 = self.sdf.distance(r.at(t));
+        let side = if d0.abs() < HIT_EPSILON {
+            let side = if self.normal(r.at(t)).dot(r.dir) > 0.0 { 1.0 } else { -1.0 };
+            t += 10.0 * HIT_EPSILON / ray_length;
+            side
+        } else {
+            d0.signum()
+        };
+
+        for _ in 0..MAX_STEPS {
+            let p = r.at(t);
+            let d = side * self.sdf.distance(p);
+            if d < HIT_EPSILON {
+                rec.t = t;
+                rec.p = p;
+                rec.material = Some(self.mat.as_ref());
+                let outward_normal = self.normal(p);
+                rec.set_face_normal(r, &outward_normal);
+                return true
+            }
+            t += d / ray_length;
+            if t > t_exit {
+                return false
+            }
+        }
+
+        false
+    }
+
+}
+
+impl Animated for SdfShape {
+
+    fn update(&mut self, _time: f64) {}
+
+}
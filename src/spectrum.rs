@@ -0,0 +1,225 @@
+use prisma::Rgb;
+use rand::Rng;
+
+use crate::color::ColorSpace;
+
+
+pub const LAMBDA_MIN: f64 = 360.0;
+pub const LAMBDA_MAX: f64 = 830.0;
+
+// Integral of the CIE 1931 y bar function, making the Y of a unit spectrum one
+const CIE_Y_INTEGRAL: f64 = 106.856_895;
+
+const XYZ_TO_REC709: [[f64; 3]; 3] = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266_0, 1.876_010_8, 0.041_556_0],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2]
+];
+
+
+// Wavelengths in nanometers a path is traced at, packed into the three channels of
+// the colors it carries. The first is the hero wavelength, which decides directions
+// that depend on wavelength, such as refraction with dispersion.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Wavelengths {
+    pub lambda: [f64; 3],
+    pub pdf: [f64; 3],
+    // Working space of the colors uplifted to and converted back from these wavelengths
+    pub space: ColorSpace
+}
+
+impl Wavelengths {
+
+    // Stratified samples of a density following the sensitivity of the eye
+    pub fn sample(space: ColorSpace) -> Self {
+        let u: f64 = rand::thread_rng().gen();
+        let mut lambda = [0.0; 3];
+        let mut pdf = [0.0; 3];
+        for i in 0..3 {
+            let ui = (u + i as f64 / 3.0).fract();
+            lambda[i] = sample_visible(ui);
+            pdf[i] = visible_pdf(lambda[i]);
+        }
+        Wavelengths { lambda, pdf, space }
+    }
+
+    // Keeps the hero wavelength only, once the path has taken a direction the others
+    // would not have. Returns the attenuation making up for the dropped ones.
+    pub fn terminate_secondary(&mut self) -> Rgb<f64> {
+        let weight = if self.is_hero_only() { 1.0 } else { 3.0 };
+        self.pdf[1] = 0.0;
+        self.pdf[2] = 0.0;
+        Rgb::new(weight, 0.0, 0.0)
+    }
+
+    pub fn is_hero_only(&self) -> bool {
+        self.pdf[1] == 0.0 && self.pdf[2] == 0.0
+    }
+
+    // Spectral radiance sampled at these wavelengths, as a color in the working space
+    pub fn to_rgb(&self, l: &Rgb<f64>) -> Rgb<f64> {
+        let values = [l.red(), l.green(), l.blue()];
+        let mut xyz = [0.0; 3];
+        for i in 0..3 {
+            if self.pdf[i] <= 0.0 || values[i] == 0.0 {
+                continue
+            }
+            let (x, y, z) = cie_xyz(self.lambda[i]);
+            let k = values[i] / (self.pdf[i] * 3.0 * CIE_Y_INTEGRAL);
+            xyz[0] += x * k;
+            xyz[1] += y * k;
+            xyz[2] += z * k;
+        }
+
+        // Balanced so that a constant spectrum, which is what white uplifts to, stays white
+        let m = XYZ_TO_REC709;
+        let rgb = Rgb::new(
+            (m[0][0] * xyz[0] + m[0][1] * xyz[1] + m[0][2] * xyz[2]) / (m[0][0] + m[0][1] + m[0][2]),
+            (m[1][0] * xyz[0] + m[1][1] * xyz[1] + m[1][2] * xyz[2]) / (m[1][0] + m[1][1] + m[1][2]),
+            (m[2][0] * xyz[0] + m[2][1] * xyz[1] + m[2][2] * xyz[2]) / (m[2][0] + m[2][1] + m[2][2])
+        );
+        ColorSpace::LinearSrgb.convert(&rgb, self.space)
+    }
+
+}
+
+
+// Values of the working space color `c` at the wavelengths of a spectral path, or `c`
+// itself for RGB paths
+pub fn sample_rgb(c: &Rgb<f64>, wavelengths: &Option<Wavelengths>) -> Rgb<f64> {
+    match wavelengths {
+        None => *c,
+        Some(w) => {
+            let c = w.space.convert(c, ColorSpace::LinearSrgb);
+            Rgb::new(
+                uplift(&c, w.lambda[0]),
+                uplift(&c, w.lambda[1]),
+                uplift(&c, w.lambda[2])
+            )
+        }
+    }
+}
+
+
+// Values of the raw per-channel coefficients `c`, such as absorption, at the wavelengths
+// of a spectral path, or `c` itself for RGB paths. They are not colors, so they skip
+// the conversion out of the working space, and negative ones are clamped to zero.
+pub fn sample_coefficients(c: &Rgb<f64>, wavelengths: &Option<Wavelengths>) -> Rgb<f64> {
+    let c = Rgb::new(c.red().max(0.0), c.green().max(0.0), c.blue().max(0.0));
+    match wavelengths {
+        None => c,
+        Some(w) => Rgb::new(uplift(&c, w.lambda[0]), uplift(&c, w.lambda[1]), uplift(&c, w.lambda[2]))
+    }
+}
+
+
+fn sample_visible(u: f64) -> f64 {
+    538.0 - 138.888_889 * (0.856_910_62 - 1.827_501_97 * u).atanh()
+}
+
+
+fn visible_pdf(lambda: f64) -> f64 {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0
+    }
+    let c = (0.0072 * (lambda - 538.0)).cosh();
+    0.003_939_804_2 / (c * c)
+}
+
+
+// Piecewise Gaussian fit of the CIE 1931 color matching functions by Wyman, Sloan
+// and Shirley
+fn cie_xyz(lambda: f64) -> (f64, f64, f64) {
+    let g = |mu: f64, sigma1: f64, sigma2: f64| {
+        let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+
+    let x = 1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2);
+    let y = 0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1);
+    let z = 1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+
+// Smits' basis spectra over ten bins spanning 380 to 720 nm
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+
+// Value at `lambda` of Smits' smooth spectrum for the linear sRGB color `c`
+fn uplift(c: &Rgb<f64>, lambda: f64) -> f64 {
+    let t = ((lambda - 380.0) / (720.0 - 380.0) * 9.0).max(0.0).min(9.0);
+    let i = (t as usize).min(8);
+    let f = t - i as f64;
+    let basis = |b: &[f64; 10]| b[i] * (1.0 - f) + b[i + 1] * f;
+
+    let (r, g, b) = (c.red(), c.green(), c.blue());
+    if r <= g && r <= b {
+        r * basis(&SMITS_WHITE) + if g <= b {
+            (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+        } else {
+            (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        g * basis(&SMITS_WHITE) + if r <= b {
+            (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+        } else {
+            (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+        }
+    } else {
+        b * basis(&SMITS_WHITE) + if r <= g {
+            (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+        } else {
+            (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn white_round_trips_through_spectral_samples() {
+        let white = Rgb::new(1.0, 1.0, 1.0);
+        let n = 200_000;
+        for &space in [ColorSpace::LinearSrgb, ColorSpace::AcesCg, ColorSpace::Rec2020].iter() {
+            let mut sum = [0.0; 3];
+            for _ in 0..n {
+                let w = Wavelengths::sample(space);
+                let c = w.to_rgb(&sample_rgb(&white, &Some(w)));
+                sum[0] += c.red();
+                sum[1] += c.green();
+                sum[2] += c.blue();
+            }
+            for s in sum.iter() {
+                assert!((s / n as f64 - 1.0).abs() < 0.01, "{:?} {:?}", space, sum.map(|s| s / n as f64));
+            }
+        }
+    }
+
+    #[test]
+    fn coefficients_stay_raw_and_non_negative() {
+        for _ in 0..10_000 {
+            let w = Wavelengths::sample(ColorSpace::Rec2020);
+            let flat = sample_coefficients(&Rgb::new(0.5, 0.5, 0.5), &Some(w));
+            let saturated = sample_coefficients(&Rgb::new(0.0, 0.0, 4.0), &Some(w));
+            for v in [flat.red(), flat.green(), flat.blue()].iter() {
+                assert!((v - 0.5).abs() < 0.001, "{:?} at {:?}", flat, w.lambda);
+            }
+            for v in [saturated.red(), saturated.green(), saturated.blue()].iter() {
+                assert!(*v >= 0.0, "{:?} at {:?}", saturated, w.lambda);
+            }
+        }
+        let rgb = sample_coefficients(&Rgb::new(0.3, -1.0, 2.0), &None);
+        assert_eq!((rgb.red(), rgb.green(), rgb.blue()), (0.3, 0.0, 2.0));
+    }
+
+}
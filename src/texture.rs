@@ -0,0 +1,103 @@
+use cgmath::Point3;
+use prisma::Rgb;
+use image::{ImageError, RgbImage};
+use num::clamp;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::color::{Color, ColorSpace};
+
+
+pub trait Texture : Send + Sync {
+    fn value(&self, u: f64, v: f64, p: &Point3<f64>) -> Rgb<f64>;
+}
+
+
+pub struct SolidColor {
+    color: Rgb<f64>
+}
+
+impl SolidColor {
+
+    // Converted into the working space `space` the scene is rendered in
+    pub fn new<C: Into<Color>>(color: C, space: ColorSpace) -> Self {
+        SolidColor { color: color.into().to(space) }
+    }
+
+}
+
+impl Texture for SolidColor {
+
+    fn value(&self, _u: f64, _v: f64, _p: &Point3<f64>) -> Rgb<f64> {
+        self.color
+    }
+
+}
+
+
+// Alternates between two textures in a 3D grid of cubes `scale` wide
+pub struct CheckerTexture {
+    scale: f64,
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>
+}
+
+impl CheckerTexture {
+
+    pub fn new(scale: f64, even: Arc<dyn Texture>, odd: Arc<dyn Texture>) -> Self {
+        CheckerTexture { scale, even, odd }
+    }
+
+}
+
+impl Texture for CheckerTexture {
+
+    fn value(&self, u: f64, v: f64, p: &Point3<f64>) -> Rgb<f64> {
+        let cell = |x: f64| (x / self.scale).floor() as i64;
+        if (cell(p.x) + cell(p.y) + cell(p.z)) % 2 == 0 {
+            self.even.value(u, v, p)
+        } else {
+            self.odd.value(u, v, p)
+        }
+    }
+
+}
+
+
+pub struct ImageTexture {
+    image: RgbImage,
+    space: ColorSpace,
+    working_space: ColorSpace
+}
+
+impl ImageTexture {
+
+    // `space` tags the pixel values, usually `Srgb` for 8-bit color images, and
+    // `working_space` is the one the scene is rendered in
+    pub fn load<P: AsRef<Path>>(path: P, space: ColorSpace, working_space: ColorSpace) -> Result<Self, ImageError> {
+        Ok(ImageTexture { image: image::open(path)?.to_rgb8(), space, working_space })
+    }
+
+}
+
+impl Texture for ImageTexture {
+
+    fn value(&self, u: f64, v: f64, _p: &Point3<f64>) -> Rgb<f64> {
+        let (width, height) = self.image.dimensions();
+        let u = clamp(u, 0.0, 1.0);
+        let v = 1.0 - clamp(v, 0.0, 1.0);
+
+        let i = ((u * width as f64) as u32).min(width - 1);
+        let j = ((v * height as f64) as u32).min(height - 1);
+        let pixel = self.image.get_pixel(i, j);
+
+        let scale = 1.0 / 255.0;
+        let rgb = Rgb::new(
+            pixel[0] as f64 * scale,
+            pixel[1] as f64 * scale,
+            pixel[2] as f64 * scale
+        );
+        self.space.convert(&rgb, self.working_space)
+    }
+
+}
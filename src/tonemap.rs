@@ -0,0 +1,98 @@
+use prisma::Rgb;
+use num::clamp;
+
+use crate::color::srgb_oetf;
+use crate::util::luminance;
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operator {
+    // Values above one are clipped
+    Clamp,
+    // Luminance mapped by L / (1 + L)
+    Reinhard,
+    // Reinhard reaching one at luminance `white` instead of infinity
+    ExtendedReinhard { white: f64 },
+    // Narkowicz's fit of the ACES reference rendering transform
+    AcesFilmic,
+    // Hable's Uncharted 2 curve
+    Hable
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ToneMapper {
+    // Exposure adjustment in stops
+    pub exposure: f64,
+    pub operator: Operator
+}
+
+impl ToneMapper {
+
+    pub fn new(exposure: f64, operator: Operator) -> Self {
+        ToneMapper { exposure, operator }
+    }
+
+    // Display referred linear color in [0, 1] for a scene referred `c`
+    pub fn map(&self, c: &Rgb<f64>) -> Rgb<f64> {
+        let k = 2f64.powf(self.exposure);
+        let c = Rgb::new(c.red() * k, c.green() * k, c.blue() * k);
+
+        let mapped = match self.operator {
+            Operator::Clamp => c,
+            Operator::Reinhard => scale_luminance(&c, |l| l / (1.0 + l)),
+            Operator::ExtendedReinhard { white } =>
+                scale_luminance(&c, |l| l * (1.0 + l / (white * white)) / (1.0 + l)),
+            Operator::AcesFilmic => per_channel(&c, aces_filmic),
+            Operator::Hable => {
+                let white_scale = 1.0 / hable_partial(11.2);
+                per_channel(&c, |v| hable_partial(2.0 * v) * white_scale)
+            }
+        };
+
+        per_channel(&mapped, |v| clamp(v, 0.0, 1.0))
+    }
+
+    // Tone mapped and sRGB encoded 8-bit color
+    pub fn to_rgb8(&self, c: &Rgb<f64>) -> [u8; 3] {
+        let m = self.map(c);
+        let quantize = |v: f64| (255.0 * srgb_oetf(v) + 0.5) as u8;
+        [quantize(m.red()), quantize(m.green()), quantize(m.blue())]
+    }
+
+}
+
+impl Default for ToneMapper {
+
+    fn default() -> Self {
+        ToneMapper::new(0.0, Operator::Clamp)
+    }
+
+}
+
+
+fn scale_luminance<F: Fn(f64) -> f64>(c: &Rgb<f64>, f: F) -> Rgb<f64> {
+    let l = luminance(c);
+    if l <= 0.0 {
+        return Rgb::new(0.0, 0.0, 0.0)
+    }
+    let k = f(l) / l;
+    Rgb::new(c.red() * k, c.green() * k, c.blue() * k)
+}
+
+
+fn per_channel<F: Fn(f64) -> f64>(c: &Rgb<f64>, f: F) -> Rgb<f64> {
+    Rgb::new(f(c.red()), f(c.green()), f(c.blue()))
+}
+
+
+fn aces_filmic(x: f64) -> f64 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+
+fn hable_partial(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
@@ -2,7 +2,6 @@ use cgmath::{Vector3, InnerSpace, vec3};
 use prisma::Rgb;
 use rand::{Rng, thread_rng};
 use std::ops::Range;
-use num::clamp;
 
 
 pub fn random_vec_in_unit_sphere() -> Vector3<f64> {
@@ -48,6 +47,15 @@ pub fn random_unit_vec() -> Vector3<f64> {
 }
 
 
+pub fn random_cosine_direction() -> Vector3<f64> {
+    let r1: f64 = thread_rng().gen();
+    let r2: f64 = thread_rng().gen();
+    let phi = 2.0 * std::f64::consts::PI * r1;
+    let r = r2.sqrt();
+    vec3(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
+}
+
+
 pub fn random_color() -> Rgb<f64> {
     Rgb::new(
         thread_rng().gen(),
@@ -72,31 +80,59 @@ pub fn vec_near_zero(v: Vector3<f64>) -> bool {
 }
 
 
-pub fn to_color(value: &Rgb<f64>, num_samples: usize) -> u32 {
-    let scale = 1.0 / num_samples as f64;
-    let r = (value.red() * scale).sqrt();
-    let g = (value.green() * scale).sqrt();
-    let b = (value.blue() * scale).sqrt();
+pub fn add_colors(a: &Rgb<f64>, b: &Rgb<f64>) -> Rgb<f64> {
+    Rgb::new(
+        a.red() + b.red(),
+        a.green() + b.green(),
+        a.blue() + b.blue()
+    )
+}
+
 
-    (((256.0 * clamp(r, 0.0, 0.999)) as u32) << 16) |
-        (((256.0 * clamp(g, 0.0, 0.999)) as u32) << 8) |
-        ((256.0 * clamp(b, 0.0, 0.999)) as u32)
+pub fn mul_colors(a: &Rgb<f64>, b: &Rgb<f64>) -> Rgb<f64> {
+    Rgb::new(
+        a.red() * b.red(),
+        a.green() * b.green(),
+        a.blue() * b.blue()
+    )
 }
 
 
-pub fn to_rgb(color: u32) -> [u8; 3] {
-    [
-        (color >> 16) as u8,
-        (color >> 8) as u8,
-        color as u8
-    ]
+pub fn scale_color(c: &Rgb<f64>, k: f64) -> Rgb<f64> {
+    Rgb::new(c.red() * k, c.green() * k, c.blue() * k)
 }
 
 
-pub fn add_colors(a: &Rgb<f64>, b: &Rgb<f64>) -> Rgb<f64> {
-    Rgb::new(
-        a.red() + b.red(),
-        a.green() + b.green(),
-        a.blue() + b.blue()
-    )
+// Rec. 709 luminance
+pub fn luminance(c: &Rgb<f64>) -> f64 {
+    0.2126 * c.red() + 0.7152 * c.green() + 0.0722 * c.blue()
+}
+
+
+pub fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a * (1.0 - t) + b * t
+}
+
+
+#[derive(Clone, Copy)]
+pub struct Onb {
+    pub u: Vector3<f64>,
+    pub v: Vector3<f64>,
+    pub w: Vector3<f64>
+}
+
+impl Onb {
+
+    pub fn from_w(n: Vector3<f64>) -> Self {
+        let w = n.normalize();
+        let a = if w.x.abs() > 0.9 { vec3(0.0, 1.0, 0.0) } else { vec3(1.0, 0.0, 0.0) };
+        let v = w.cross(a).normalize();
+        let u = w.cross(v);
+        Onb { u, v, w }
+    }
+
+    pub fn local(&self, a: f64, b: f64, c: f64) -> Vector3<f64> {
+        self.u * a + self.v * b + self.w * c
+    }
+
 }
@@ -0,0 +1,508 @@
+use cgmath::{InnerSpace, Point3, vec3};
+use prisma::Rgb;
+use rand::Rng;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::animation::Animated;
+use crate::color::{Color, ColorSpace};
+use crate::raytracing::Ray;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::{Dielectric, HenyeyGreenstein, Isotropic, Material, ScatterRecord, isolate_channel,
+                      isolate_given_channel};
+use crate::scene::SceneObject;
+use crate::spectrum::{sample_coefficients, sample_rgb};
+use crate::util::{mul_colors, scale_color};
+
+
+pub struct ConstantMedium {
+    boundary: Box<dyn SceneObject>,
+    neg_inv_density: f64,
+    phase_function: Arc<dyn Material>
+}
+
+impl ConstantMedium {
+
+    pub fn new<C: Into<Color>>(boundary: Box<dyn SceneObject>, density: f64, albedo: C, space: ColorSpace) -> Self {
+        ConstantMedium {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase_function: Arc::new(Isotropic::new(albedo, space))
+        }
+    }
+
+}
+
+impl Hittable for ConstantMedium {
+
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        let mut rec1 = HitRecord::new();
+        let mut rec2 = HitRecord::new();
+
+        // Find where the ray enters and leaves the boundary, wherever the origin is
+        if !self.boundary.hit(r, -std::f64::INFINITY, std::f64::INFINITY, &mut rec1) {
+            return false
+        }
+        if !self.boundary.hit(r, rec1.t + 0.0001, std::f64::INFINITY, &mut rec2) {
+            return false
+        }
+
+        let t_enter = rec1.t.max(t_min).max(0.0);
+        let t_exit = rec2.t.min(t_max);
+        if t_enter >= t_exit {
+            return false
+        }
+
+        let ray_length = r.dir.magnitude();
+        let distance_inside_boundary = (t_exit - t_enter) * ray_length;
+        let hit_distance = self.neg_inv_density * rand::thread_rng().gen::<f64>().ln();
+        if hit_distance > distance_inside_boundary {
+            return false
+        }
+
+        rec.t = t_enter + hit_distance / ray_length;
+        rec.p = r.at(rec.t);
+        rec.normal = vec3(1.0, 0.0, 0.0);
+        rec.front_face = true;
+        rec.material = Some(self.phase_function.as_ref());
+        true
+    }
+
+}
+
+impl Animated for ConstantMedium {
+
+    fn update(&mut self, time: f64) {
+        self.boundary.update(time);
+    }
+
+}
+
+
+// Interface of a `Subsurface` object. Refraction into the object narrows the path
+// down to one channel, whose mean free path the walk inside then follows.
+struct SubsurfaceBoundary {
+    interface: Dielectric
+}
+
+impl Material for SubsurfaceBoundary {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        if !self.interface.scatter(r, rec, srec) {
+            return false
+        }
+        if rec.front_face && srec.specular_ray.dir.dot(rec.normal) < 0.0 {
+            let ray = &mut srec.specular_ray;
+            let weights = isolate_channel(&mut ray.wavelengths, &mut ray.channel);
+            srec.attenuation = mul_colors(&srec.attenuation, &weights);
+        }
+        true
+    }
+
+}
+
+
+// Scattering that ends a step through a `Subsurface` object along channel `channel` by
+// a path that started inside, and so was never narrowed down on the way in. The
+// scattered ray is narrowed down to the channel, for which directions of `inner` are
+// sampled here, making the event a specular one.
+struct Narrowing {
+    inner: Arc<dyn Material>,
+    channel: usize
+}
+
+impl Material for Narrowing {
+
+    fn scatter(&self, r: &Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        if !self.inner.scatter(r, rec, srec) {
+            return false
+        }
+        if !srec.is_specular {
+            let pdf = srec.pdf.take().unwrap();
+            let scattered = Ray { origin: rec.p, dir: pdf.generate(), wavelengths: r.wavelengths, channel: r.channel };
+            let pdf_value = pdf.value(&scattered.dir);
+            if pdf_value <= 0.0 {
+                return false
+            }
+            srec.attenuation = scale_color(&self.inner.eval(r, rec, &scattered), 1.0 / pdf_value);
+            srec.is_specular = true;
+            srec.specular_ray = scattered;
+        }
+
+        let ray = &mut srec.specular_ray;
+        let weights = isolate_given_channel(&mut ray.wavelengths, &mut ray.channel, self.channel);
+        srec.attenuation = mul_colors(&srec.attenuation, &weights);
+        true
+    }
+
+    fn is_volumetric(&self) -> bool {
+        self.inner.is_volumetric()
+    }
+
+}
+
+
+// Random walk subsurface scattering for skin, wax, marble and the like. Light refracts
+// into the closed `boundary`, scatters through the medium inside with a mean free path
+// per channel until it reaches the boundary again, and refracts out. Every step of the
+// walk counts against the renderer's depth limit, so mean free paths should not be
+// much shorter than a tenth of the object's size.
+pub struct Subsurface {
+    boundary: Box<dyn SceneObject>,
+    sigma_t: Rgb<f64>,
+    surface: Arc<dyn Material>,
+    phase_function: Arc<dyn Material>,
+    // Surface and phase function narrowing paths that started inside, per channel
+    narrowing: Vec<(Narrowing, Narrowing)>
+}
+
+impl Subsurface {
+
+    // `mean_free_path` holds raw distances per channel of the working space, in scene
+    // units, and is not converted like colors are.
+    pub fn new<A: Into<Color>>(boundary: Box<dyn SceneObject>, ior: f64, mean_free_path: Rgb<f64>,
+                               albedo: A, g: f64, space: ColorSpace) -> Self {
+        let mfp = mean_free_path;
+        assert!(mfp.red() > 0.0 && mfp.green() > 0.0 && mfp.blue() > 0.0, "mean free paths must be positive");
+        let surface: Arc<dyn Material> = Arc::new(SubsurfaceBoundary { interface: Dielectric::new(ior) });
+        let phase_function: Arc<dyn Material> = Arc::new(HenyeyGreenstein::new(albedo, g, space));
+        let narrowing = (0..3).map(|channel| (
+            Narrowing { inner: surface.clone(), channel },
+            Narrowing { inner: phase_function.clone(), channel }
+        )).collect();
+        Subsurface {
+            boundary,
+            sigma_t: Rgb::new(1.0 / mfp.red(), 1.0 / mfp.green(), 1.0 / mfp.blue()),
+            surface,
+            phase_function,
+            narrowing
+        }
+    }
+
+    // Extinction along the channel the walk follows, `r` being narrowed down to it
+    fn extinction(&self, r: &Ray) -> f64 {
+        let sigma_t = sample_coefficients(&self.sigma_t, &r.wavelengths);
+        match r.channel {
+            Some(c) => [sigma_t.red(), sigma_t.green(), sigma_t.blue()][c],
+            None => sigma_t.red()
+        }
+    }
+
+}
+
+impl Hittable for Subsurface {
+
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        let mut surface_rec = HitRecord::new();
+        if !self.boundary.hit(r, t_min, std::f64::INFINITY, &mut surface_rec) {
+            return false
+        }
+
+        // Paths that started inside were never narrowed down on the way in. This step
+        // follows a channel picked here, and whatever it ends at narrows the path to it.
+        let narrowed = r.channel.is_some() || matches!(r.wavelengths, Some(w) if w.is_hero_only());
+        let mut walk = *r;
+        let (surface, phase_function) = if narrowed || surface_rec.front_face {
+            (self.surface.as_ref(), self.phase_function.as_ref())
+        } else {
+            let c = rand::thread_rng().gen_range(0..3);
+            isolate_given_channel(&mut walk.wavelengths, &mut walk.channel, c);
+            let (surface, phase_function) = &self.narrowing[c];
+            (surface as &dyn Material, phase_function as &dyn Material)
+        };
+
+        // Rays from inside may scatter before reaching the surface, rays from outside see it
+        if !surface_rec.front_face {
+            let ray_length = r.dir.magnitude();
+            let t = t_min - (1.0 - rand::thread_rng().gen::<f64>()).ln() / (self.extinction(&walk) * ray_length);
+            if t < surface_rec.t.min(t_max) {
+                rec.t = t;
+                rec.p = r.at(t);
+                rec.normal = vec3(1.0, 0.0, 0.0);
+                rec.front_face = true;
+                rec.material = Some(phase_function);
+                return true
+            }
+        }
+
+        if surface_rec.t >= t_max {
+            return false
+        }
+        *rec = surface_rec;
+        rec.material = Some(surface);
+        true
+    }
+
+}
+
+impl Animated for Subsurface {
+
+    fn update(&mut self, time: f64) {
+        self.boundary.update(time);
+    }
+
+}
+
+
+// Dense density grid. The raw file format is three little-endian u32 dimensions
+// (nx, ny, nz) followed by nx * ny * nz little-endian f32 values, x varying fastest.
+pub struct DensityGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    data: Vec<f32>,
+    max_density: f64
+}
+
+impl DensityGrid {
+
+    pub fn new(nx: usize, ny: usize, nz: usize, data: Vec<f32>) -> Self {
+        assert_eq!(data.len(), nx * ny * nz, "density grid size does not match its dimensions");
+        // The majorant of delta tracking relies on densities being non-negative
+        let data: Vec<f32> = data.into_iter().map(|d| d.max(0.0)).collect();
+        let max_density = data.iter().fold(0.0f32, |m, &d| m.max(d)) as f64;
+        DensityGrid { nx, ny, nz, data, max_density }
+    }
+
+    pub fn from_fn<F: Fn(f64, f64, f64) -> f64>(nx: usize, ny: usize, nz: usize, f: F) -> Self {
+        let mut data = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    data.push(f(
+                        (x as f64 + 0.5) / nx as f64,
+                        (y as f64 + 0.5) / ny as f64,
+                        (z as f64 + 0.5) / nz as f64
+                    ) as f32);
+                }
+            }
+        }
+        DensityGrid::new(nx, ny, nz, data)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        let dim = |i: usize| {
+            u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]) as usize
+        };
+        let (nx, ny, nz) = (dim(0), dim(4), dim(8));
+        if nx == 0 || ny == 0 || nz == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "density grid has an empty dimension"))
+        }
+
+        // Check the size against the file before trusting the header with an allocation
+        let size = nx.checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .and_then(|n| n.checked_mul(4))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "density grid dimensions are too large"))?;
+        if (size as u64).checked_add(12) != Some(file_len) {
+            return Err(Error::new(ErrorKind::InvalidData, "density grid size does not match the file length"))
+        }
+
+        let mut bytes = vec![0u8; size];
+        reader.read_exact(&mut bytes)?;
+        let data: Vec<f32> = bytes.chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        if data.iter().any(|d| !(d.is_finite() && *d >= 0.0)) {
+            return Err(Error::new(ErrorKind::InvalidData, "density grid has negative or non-finite values"))
+        }
+
+        Ok(DensityGrid::new(nx, ny, nz, data))
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.data[(z * self.ny + y) * self.nx + x] as f64
+    }
+
+    // Trilinear lookup at normalized grid coordinates in [0, 1]^3, voxel values
+    // being stored at cell centers
+    pub fn density(&self, u: f64, v: f64, w: f64) -> f64 {
+        let axis = |c: f64, n: usize| {
+            let x = (c * n as f64 - 0.5).max(0.0).min((n - 1) as f64);
+            let i = (x.floor() as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), x - i as f64)
+        };
+        let (x0, x1, fx) = axis(u, self.nx);
+        let (y0, y1, fy) = axis(v, self.ny);
+        let (z0, z1, fz) = axis(w, self.nz);
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let c00 = lerp(self.voxel(x0, y0, z0), self.voxel(x1, y0, z0), fx);
+        let c10 = lerp(self.voxel(x0, y1, z0), self.voxel(x1, y1, z0), fx);
+        let c01 = lerp(self.voxel(x0, y0, z1), self.voxel(x1, y0, z1), fx);
+        let c11 = lerp(self.voxel(x0, y1, z1), self.voxel(x1, y1, z1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+
+}
+
+
+// Terminates paths on absorption events, returning the medium's emission
+struct MediumEmission {
+    emission: Rgb<f64>
+}
+
+impl Material for MediumEmission {
+
+    fn scatter(&self, _r: &Ray, _rec: &HitRecord, _srec: &mut ScatterRecord) -> bool {
+        false
+    }
+
+    fn emitted(&self, r: &Ray, _rec: &HitRecord) -> Rgb<f64> {
+        sample_rgb(&self.emission, &r.wavelengths)
+    }
+
+}
+
+
+pub struct HeterogeneousMedium {
+    grid: DensityGrid,
+    bounds: Aabb,
+    sigma_a: f64,
+    sigma_s: f64,
+    majorant: f64,
+    phase_function: Arc<dyn Material>,
+    absorption: Arc<dyn Material>
+}
+
+impl HeterogeneousMedium {
+
+    pub fn new<A: Into<Color>>(grid: DensityGrid, bounds: Aabb, sigma_a: f64, sigma_s: f64,
+                               albedo: A, g: f64, space: ColorSpace) -> Self {
+        let majorant = (sigma_a + sigma_s) * grid.max_density;
+        HeterogeneousMedium {
+            grid,
+            bounds,
+            sigma_a,
+            sigma_s,
+            majorant,
+            phase_function: Arc::new(HenyeyGreenstein::new(albedo, g, space)),
+            absorption: Arc::new(MediumEmission { emission: Rgb::new(0.0, 0.0, 0.0) })
+        }
+    }
+
+    // Radiance emitted where absorption happens
+    pub fn with_emission<E: Into<Color>>(mut self, emission: E, space: ColorSpace) -> Self {
+        self.absorption = Arc::new(MediumEmission { emission: emission.into().to(space) });
+        self
+    }
+
+    fn density_at(&self, p: Point3<f64>) -> f64 {
+        let size = self.bounds.max - self.bounds.min;
+        let local = p - self.bounds.min;
+        self.grid.density(local.x / size.x, local.y / size.y, local.z / size.z)
+    }
+
+}
+
+impl Hittable for HeterogeneousMedium {
+
+    // Delta tracking: tentative collisions are sampled against the majorant and
+    // classified as absorption, scattering or null collisions by the local coefficients
+    fn hit<'a>(&'a self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord<'a>) -> bool {
+        if self.majorant <= 0.0 {
+            return false
+        }
+
+        let (t_enter, t_exit) = match self.bounds.intersect(r, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false
+        };
+
+        let mut rng = rand::thread_rng();
+        let ray_length = r.dir.magnitude();
+        let mut t = t_enter;
+
+        loop {
+            t -= (1.0 - rng.gen::<f64>()).ln() / (self.majorant * ray_length);
+            if t >= t_exit {
+                return false
+            }
+
+            let p = r.at(t);
+            let density = self.density_at(p);
+            let xi = rng.gen::<f64>() * self.majorant;
+
+            let material = if xi < self.sigma_a * density {
+                &self.absorption
+            } else if xi < (self.sigma_a + self.sigma_s) * density {
+                &self.phase_function
+            } else {
+                continue
+            };
+
+            rec.t = t;
+            rec.p = p;
+            rec.normal = vec3(1.0, 0.0, 0.0);
+            rec.front_face = true;
+            rec.material = Some(material.as_ref());
+            return true
+        }
+    }
+
+}
+
+impl Animated for HeterogeneousMedium {
+
+    fn update(&mut self, _time: f64) {}
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::point3;
+
+    use crate::aov::SampleAovs;
+    use crate::geometry::Sphere;
+    use crate::raytracing::{PathTracer, SamplingStrategy};
+    use crate::scene::World;
+    use crate::util::random_unit_vec;
+
+    #[test]
+    fn paths_starting_inside_follow_each_mean_free_path() {
+        // An absorbing sphere without refraction in front of a white background, seen
+        // from its center, lets through what survives its radius in each channel
+        let space = ColorSpace::LinearSrgb;
+        let mut world = World::new(space);
+        world.background = Some(Rgb::new(1.0, 1.0, 1.0));
+        let mean_free_path = Rgb::new(0.5, 1.0, 2.0);
+        world.objects.push(Box::new(Subsurface::new(
+            Box::new(Sphere { center: point3(0.0, 0.0, 0.0), radius: 1.0, mat: Arc::new(Dielectric::new(1.0)) }),
+            1.0,
+            mean_free_path,
+            Rgb::new(0.0, 0.0, 0.0),
+            0.0,
+            space
+        )));
+
+        let integrator = PathTracer { max_depth: 4, rr_min_depth: 4, sampling: SamplingStrategy::Bsdf };
+        let n = 100_000;
+        let mut sum = [0.0; 3];
+        for _ in 0..n {
+            let r = Ray { origin: point3(0.0, 0.0, 0.0), dir: random_unit_vec(), wavelengths: None, channel: None };
+            let c = integrator.ray_color(&r, &world, &mut SampleAovs::new());
+            sum[0] += c.red();
+            sum[1] += c.green();
+            sum[2] += c.blue();
+        }
+
+        let mfp = [mean_free_path.red(), mean_free_path.green(), mean_free_path.blue()];
+        for i in 0..3 {
+            let expected = (-1.0 / mfp[i]).exp();
+            assert!((sum[i] / n as f64 - expected).abs() < 0.015, "channel {}: {} instead of {}",
+                    i, sum[i] / n as f64, expected);
+        }
+    }
+
+}